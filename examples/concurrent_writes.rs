@@ -0,0 +1,55 @@
+//! Compares the throughput of several threads writing through a plain `Mutex<LSMEngine>` against a
+//! `ConcurrentEngine`, which commits their writes in groups.
+//!
+//! cargo run --release --example concurrent_writes -- [threads] [writes per thread]
+use lsm_engine::{ConcurrentEngine, LSMBuilder, SkipListRep};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+fn builder(wal: &str) -> LSMBuilder {
+    let _ = std::fs::remove_file(wal);
+    LSMBuilder::new().segment_size(20_000).inmemory_capacity(10_000).wal_path(wal).wal_sync(true)
+}
+
+fn run<F: Fn(String, String) + Send + Sync + 'static>(name: &str, threads: usize, writes: usize, write: F) {
+    let write = Arc::new(write);
+    let started = Instant::now();
+    let handles = (0..threads)
+        .map(|thread| {
+            let write = write.clone();
+            thread::spawn(move || {
+                for i in 0..writes {
+                    write(format!("t{}-k{:06}", thread, i), "v".repeat(100));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+    println!("{:<28} {:>10.0} writes/s", name, (threads * writes) as f64 / elapsed.as_secs_f64());
+}
+
+fn main() {
+    let args: Vec<usize> = std::env::args().skip(1).map(|arg| arg.parse().expect("expected a number")).collect();
+    let threads = args.first().copied().unwrap_or(8);
+    let writes = args.get(1).copied().unwrap_or(500);
+    println!("{} threads, {} synced writes each", threads, writes);
+
+    let single = Arc::new(Mutex::new(builder("/tmp/bench_single_wal").build()));
+    run("single writer (mutex)", threads, writes, move |key, value| single.lock().unwrap().write(key, value).unwrap());
+
+    let grouped = ConcurrentEngine::new(builder("/tmp/bench_grouped_wal").build());
+    run("group commit", threads, writes, move |key, value| grouped.write(key, value).unwrap());
+
+    let concurrent = ConcurrentEngine::new(builder("/tmp/bench_concurrent_wal").memtable_rep(SkipListRep::default()).build());
+    let stats = concurrent.clone();
+    run("group commit + skiplist", threads, writes, move |key, value| concurrent.write(key, value).unwrap());
+    println!("{} writes went out in {} groups", stats.stats().writes, stats.stats().write_groups);
+
+    for wal in ["/tmp/bench_single_wal", "/tmp/bench_grouped_wal", "/tmp/bench_concurrent_wal"] {
+        let _ = std::fs::remove_file(wal);
+    }
+}
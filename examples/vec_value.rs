@@ -1,5 +1,6 @@
 use lsm_engine::LSMBuilder;
 
+#[allow(clippy::useless_vec)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut lsm = LSMBuilder::new().
        segment_size(2000). // each sst file will have up to 2000 entries
@@ -0,0 +1,192 @@
+use crate::{ConcurrentEngine, Error, LSMEngine, Result, WriteBatch};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce(&ConcurrentEngine) + Send>;
+
+struct Slot<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// The result of a call handed to the pool, ready once a worker has made it.
+struct Pending<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        if let Some(result) = slot.result.take() {
+            return Poll::Ready(result);
+        }
+        slot.waker = Some(cx.waker().clone());
+        return Poll::Pending;
+    }
+}
+
+struct Pool {
+    engine: ConcurrentEngine,
+    //taken on drop, which tells the workers to finish
+    jobs: Mutex<Option<Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.jobs.lock().unwrap().take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(engine: ConcurrentEngine, jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(&engine),
+            Err(_) => return,
+        }
+    }
+}
+
+/// An engine for async code. Every call runs on a pool of worker threads of its own, so file I/O, flushes and the
+/// merges they set off never block the executor, whichever one it is. The workers share a [`ConcurrentEngine`], so
+/// writes from several of them are committed together.
+///
+/// Clones share the same engine and workers, which finish once the last clone is dropped.
+#[derive(Clone)]
+pub struct AsyncDb {
+    pool: Arc<Pool>,
+}
+
+impl AsyncDb {
+    /// Hands `engine` over to `threads` worker threads.
+    pub fn new(engine: LSMEngine, threads: usize) -> Self {
+        let engine = ConcurrentEngine::new(engine);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let (engine, receiver) = (engine.clone(), receiver.clone());
+                thread::spawn(move || work(engine, receiver))
+            })
+            .collect();
+        return AsyncDb { pool: Arc::new(Pool { engine, jobs: Mutex::new(Some(sender)), workers }) };
+    }
+
+    /// Runs `call` on a worker, a panic in it coming back as an error rather than a future that never completes.
+    fn call<T, F>(&self, call: F) -> Pending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ConcurrentEngine) -> Result<T> + Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        let filled = slot.clone();
+        let job: Job = Box::new(move |engine| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| call(engine))).unwrap_or(Err(Error::WorkerPanicked));
+            let mut slot = filled.lock().unwrap();
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        let jobs = self.pool.jobs.lock().unwrap();
+        jobs.as_ref().expect("the pool outlives its handles").send(job).expect("the workers outlive the pool");
+        return Pending { slot };
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        let key = key.to_owned();
+        return self.call(move |engine| engine.read(&key)).await;
+    }
+
+    pub async fn put(&self, key: String, value: String) -> Result<()> {
+        return self.call(move |engine| engine.write(key, value)).await;
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let key = key.to_owned();
+        return self.call(move |engine| engine.delete(&key)).await;
+    }
+
+    /// Applies every write in `batch` atomically.
+    pub async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        return self.call(move |engine| engine.write_batch(batch)).await;
+    }
+
+    /// The keys in `start..end` and their values, in order.
+    pub async fn scan(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        let (start, end) = (start.to_owned(), end.to_owned());
+        return self.call(move |engine| engine.scan(&start, &end)).await;
+    }
+
+    /// See [`LSMEngine::compact`].
+    pub async fn compact(&self) -> Result<()> {
+        return self.call(|engine| engine.compact()).await;
+    }
+
+    /// The engine the workers share, for the calls that have no async version.
+    pub fn engine(&self) -> &ConcurrentEngine {
+        return &self.pool.engine;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LSMBuilder;
+
+    fn db() -> AsyncDb {
+        return AsyncDb::new(LSMBuilder::new().segment_size(10).inmemory_capacity(10).build(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_calls() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let db = db();
+        db.put("k1".to_owned(), "v1".to_owned()).await?;
+        db.put("k2".to_owned(), "v2".to_owned()).await?;
+        assert_eq!(db.get("k1").await?, Some("v1".to_owned()));
+        db.delete("k1").await?;
+        assert_eq!(db.get("k1").await?, None);
+        let mut batch = WriteBatch::new();
+        batch.put("k3".to_owned(), "v3".to_owned());
+        batch.delete("k2");
+        db.write_batch(batch).await?;
+        db.compact().await?;
+        assert_eq!(db.scan("k", "l").await?, vec![("k3".to_owned(), "v3".to_owned())]);
+        assert_eq!(db.engine().stats().compactions, 1);
+        Ok(())
+    }
+
+    //a single-threaded executor keeps running other tasks while the workers write
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_concurrent_tasks() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let db = db();
+        let tasks = (0..8)
+            .map(|task| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        db.put(format!("{}-{:02}", task, i), i.to_string()).await?;
+                    }
+                    return Ok::<(), Error>(());
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await??;
+        }
+        assert_eq!(db.scan("", "~").await?.len(), 200);
+        assert_eq!(db.get("7-24").await?, Some("24".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use crate::checksum::Checksum;
+use crate::manifest::{self, Manifest};
+use crate::{Error, LSMEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keeps incremental backups of engines in a directory of its own.
+///
+/// Every backup starts as a [checkpoint](LSMEngine::checkpoint). Its files are then stored once in `shared/`, segments
+/// named after their file number and checksum and WALs after their contents, so a segment that several backups have
+/// in common takes up space only once, and a new backup only stores the segments written since the last one. Each backup is recorded in `meta/` as the
+/// list of files it references, along with the manifest to restore it with.
+///
+/// A directory's backups should all go through one `BackupEngine`, shared between threads if need be, so that deleting
+/// a backup never collects the files of one being created.
+pub struct BackupEngine {
+    dir: PathBuf,
+    //held while files move into or out of shared/, so none is collected before its backup is recorded
+    busy: Mutex<()>,
+}
+
+/// A summary of one backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u64,
+    /// When the backup was taken, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub files: usize,
+    /// The size of every file the backup references, shared with other backups or not.
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupMeta {
+    id: u64,
+    timestamp: u64,
+    manifest: Manifest,
+    files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    //its name in the data directory
+    name: String,
+    //its name in shared/
+    shared: String,
+    bytes: u64,
+    checksum: u64,
+}
+
+impl BackupMeta {
+    fn info(&self) -> BackupInfo {
+        return BackupInfo {
+            id: self.id,
+            timestamp: self.timestamp,
+            files: self.files.len(),
+            bytes: self.files.iter().map(|file| file.bytes).sum(),
+        };
+    }
+}
+
+impl BackupEngine {
+    /// Opens the backup directory `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("shared"))?;
+        fs::create_dir_all(dir.join("meta"))?;
+        return Ok(BackupEngine { dir, busy: Mutex::new(()) });
+    }
+
+    /// Backs up the current contents of `lsm`, flushing its memtable first.
+    pub fn create_backup(&self, lsm: &mut LSMEngine) -> Result<BackupInfo> {
+        let _busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        let id = self.metas()?.last().map_or(1, |meta| meta.id + 1);
+        let staging = self.dir.join(format!("staging-{}", id));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        lsm.checkpoint(&staging)?;
+        let manifest = Manifest::load(&staging)?.expect("a checkpoint always has a manifest");
+
+        //the manifest already knows the segments' checksums, but not the WAL's
+        let segments = manifest.level0.iter().chain(manifest.run.iter());
+        let mut names = segments.map(|entry| (manifest::segment_file_name(entry.file), entry.checksum)).collect::<Vec<_>>();
+        names.extend(manifest.wal.map(|file| (manifest::wal_file_name(file), None)));
+        let mut files = vec![];
+        for (name, checksum) in names {
+            let path = staging.join(&name);
+            let bytes = fs::metadata(&path)?.len();
+            let (stem, extension) = name.split_once('.').unwrap_or((&name, ""));
+            let (mut shared, checksum) = match checksum {
+                //segments keep their file numbers, which along with their checksums tell them apart
+                Some(checksum) => (format!("{}-{:016x}.{}", stem, checksum, extension), checksum),
+                //whereas every checkpoint numbers its WAL afresh, so the WAL goes by its contents alone
+                None => {
+                    let checksum = Checksum::of_file(&path)?;
+                    (format!("{:016x}-{}.{}", checksum, bytes, extension), checksum)
+                }
+            };
+            let mut target = self.dir.join("shared").join(&shared);
+            if extension == "log" && target.exists() && fs::read(&target)? != fs::read(&path)? {
+                //a different WAL of the same size and checksum
+                shared = format!("{:016x}-{}-{}.{}", checksum, bytes, id, extension);
+                target = self.dir.join("shared").join(&shared);
+            }
+            //already stored by an earlier backup
+            if !target.exists() {
+                fs::rename(&path, &target)?;
+            }
+            files.push(BackupFile { name, shared, bytes, checksum });
+        }
+        fs::remove_dir_all(&staging)?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let meta = BackupMeta { id, timestamp, manifest, files };
+        let path = self.meta_path(id);
+        let temp = path.with_extension("tmp");
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp)?;
+        serde_json::to_writer(&mut file, &meta)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        return Ok(meta.info());
+    }
+
+    /// Every backup kept, oldest first.
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>> {
+        return Ok(self.metas()?.iter().map(BackupMeta::info).collect());
+    }
+
+    /// Checks that every file backup `id` references is in place, with the size and checksum it was backed up with.
+    pub fn verify_backup(&self, id: u64) -> Result<()> {
+        let meta = self.meta(id)?;
+        for file in meta.files.iter() {
+            let path = self.dir.join("shared").join(&file.shared);
+            let corrupted = |reason: String| Error::BackupCorrupted { id, reason };
+            if !path.exists() {
+                return Err(corrupted(format!("{} is missing", file.shared)));
+            }
+            let bytes = fs::metadata(&path)?.len();
+            if bytes != file.bytes {
+                return Err(corrupted(format!("{} holds {} bytes rather than {}", file.shared, bytes, file.bytes)));
+            }
+            if Checksum::of_file(&path)? != file.checksum {
+                return Err(corrupted(format!("{} does not match its checksum", file.shared)));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Deletes backup `id`, along with the files no other backup references.
+    pub fn delete_backup(&self, id: u64) -> Result<()> {
+        self.meta(id)?;
+        fs::remove_file(self.meta_path(id))?;
+        return self.collect_garbage();
+    }
+
+    /// Deletes all but the newest `keep` backups, along with the files only they referenced.
+    pub fn purge_old_backups(&self, keep: usize) -> Result<()> {
+        let metas = self.metas()?;
+        for meta in metas.iter().take(metas.len().saturating_sub(keep)) {
+            fs::remove_file(self.meta_path(meta.id))?;
+        }
+        return self.collect_garbage();
+    }
+
+    /// Restores backup `id` into the new data directory `dir`, ready for [`LSMEngine::open`].
+    pub fn restore<P: AsRef<Path>>(&self, id: u64, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let meta = self.meta(id)?;
+        fs::create_dir(dir)?;
+        for file in meta.files.iter() {
+            let target = dir.join(&file.name);
+            fs::copy(self.dir.join("shared").join(&file.shared), &target)?;
+            File::open(&target)?.sync_all()?;
+        }
+        meta.manifest.save(dir)?;
+        return Ok(());
+    }
+
+    fn meta_path(&self, id: u64) -> PathBuf {
+        return self.dir.join("meta").join(format!("{:06}.json", id));
+    }
+
+    fn meta(&self, id: u64) -> Result<BackupMeta> {
+        let path = self.meta_path(id);
+        if !path.exists() {
+            return Err(Error::BackupNotFound { id });
+        }
+        let meta = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        return Ok(meta);
+    }
+
+    fn metas(&self) -> Result<Vec<BackupMeta>> {
+        let mut metas = vec![];
+        for entry in fs::read_dir(self.dir.join("meta"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                let meta: BackupMeta = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+                metas.push(meta);
+            }
+        }
+        metas.sort_by_key(|meta| meta.id);
+        return Ok(metas);
+    }
+
+    /// Deletes the shared files no backup references anymore.
+    fn collect_garbage(&self) -> Result<()> {
+        let _busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        let referenced = self.metas()?.into_iter().flat_map(|meta| meta.files).map(|file| file.shared).collect::<Vec<_>>();
+        for entry in fs::read_dir(self.dir.join("shared"))? {
+            let entry = entry?;
+            if !referenced.contains(&entry.file_name().to_string_lossy().into_owned()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LSMBuilder;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn write(lsm: &mut LSMEngine, range: std::ops::Range<usize>) -> Result<()> {
+        for i in range {
+            lsm.write(format!("k{:03}", i), i.to_string())?;
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn test_incremental_backups() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let builder = || LSMBuilder::new().segment_size(10).inmemory_capacity(10).compaction_trigger(100);
+        let mut lsm = builder().open(dir.path().join("db"))?;
+        let backups = BackupEngine::open(dir.path().join("backups"))?;
+        let shared = || fs::read_dir(dir.path().join("backups").join("shared")).map(|entries| entries.count());
+
+        write(&mut lsm, 0..30)?;
+        let first = backups.create_backup(&mut lsm)?;
+        let first_files = shared()?;
+        write(&mut lsm, 30..40)?;
+        let second = backups.create_backup(&mut lsm)?;
+        //only the new segment went in; the WAL is empty either way
+        assert_eq!(shared()?, first_files + 1);
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(second.files, first.files + 1);
+        assert_eq!(backups.list_backups()?, vec![first.clone(), second.clone()]);
+        backups.verify_backup(1)?;
+        backups.verify_backup(2)?;
+
+        backups.restore(1, dir.path().join("restored"))?;
+        let mut restored = builder().open(dir.path().join("restored"))?;
+        assert_eq!(restored.scan("k", "l")?.len(), 30);
+        assert_eq!(restored.read("k035")?, None);
+        assert!(backups.restore(1, dir.path().join("restored")).is_err());
+
+        backups.purge_old_backups(1)?;
+        assert_eq!(backups.list_backups()?, vec![second]);
+        assert!(matches!(backups.verify_backup(1), Err(Error::BackupNotFound { id: 1 })));
+        backups.restore(2, dir.path().join("latest"))?;
+        let mut latest = builder().open(dir.path().join("latest"))?;
+        assert_eq!(latest.scan("k", "l")?, lsm.scan("k", "l")?);
+
+        //a damaged file fails verification
+        let victim = fs::read_dir(dir.path().join("backups").join("shared"))?.next().unwrap()?.path();
+        let mut file = OpenOptions::new().append(true).open(victim)?;
+        file.write_all(b"garbage")?;
+        assert!(matches!(backups.verify_backup(2), Err(Error::BackupCorrupted { id: 2, .. })));
+
+        backups.delete_backup(2)?;
+        assert_eq!(shared()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_while_backing_up() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().segment_size(10).inmemory_capacity(10).open(dir.path().join("db"))?;
+        let backups = Arc::new(BackupEngine::open(dir.path().join("backups"))?);
+        let done = Arc::new(AtomicBool::new(false));
+        let (purging, stop) = (backups.clone(), done.clone());
+        let purger = std::thread::spawn(move || -> Result<()> {
+            while !stop.load(Ordering::Relaxed) {
+                purging.purge_old_backups(1)?;
+            }
+            return Ok(());
+        });
+        for round in 0..20 {
+            write(&mut lsm, round * 10..round * 10 + 15)?;
+            let info = backups.create_backup(&mut lsm)?;
+            //whatever the purges left of it, the newest backup is complete
+            backups.verify_backup(info.id)?;
+        }
+        done.store(true, Ordering::Relaxed);
+        purger.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::kv::KVPair;
+use crate::record;
+use crate::{Result, TOMBSTONE_VALUE};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+lazy_static! {
+    // prefix marking a WAL record as a whole batch of writes
+    static ref BATCH_MARKER: String = {
+        let rng: StdRng = SeedableRng::seed_from_u64(23);
+        rng.sample_iter(&Alphanumeric).take(20).collect::<String>()
+    };
+}
+
+/// A group of writes applied atomically: the batch is logged as a single WAL record, so recovery replays
+/// either all of it or none of it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<KVPair>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        return WriteBatch { entries: Vec::new() };
+    }
+
+    pub fn put(&mut self, key: String, value: String) {
+        self.entries.push(KVPair { key, value });
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.entries.push(KVPair {
+            key: key.to_owned(),
+            value: TOMBSTONE_VALUE.to_string(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// Refuses the batch if any value it puts is one the engine reserves.
+    pub(crate) fn check_values(&self) -> Result<()> {
+        for kv in self.entries.iter() {
+            record::check_value(&kv.key, &kv.value)?;
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        let encoded = serde_json::to_string(&self.entries).expect("key-value pairs are always serializable");
+        return format!("{}{}", *BATCH_MARKER, encoded);
+    }
+
+    pub(crate) fn decode(value: &str) -> Option<WriteBatch> {
+        let encoded = value.strip_prefix(BATCH_MARKER.as_str())?;
+        let entries = serde_json::from_str(encoded).ok()?;
+        return Some(WriteBatch { entries });
+    }
+}
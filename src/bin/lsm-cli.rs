@@ -0,0 +1,174 @@
+//! Looks into and changes a data directory from the command line:
+//!
+//! `lsm-cli <data-dir> <command> [arguments]`
+//!
+//! `dump-wal`, `dump-segment` and `verify` only read the files, and `repair` rewrites them without opening the
+//! engine, while every other command opens it, which replays and rotates the WAL. Only `put` creates the data
+//! directory if it isn't there yet.
+
+// explicit returns are the house style
+#![allow(clippy::needless_return)]
+
+use lsm_engine::{verify_dir, LSMEngine, Record, SegmentReader, WalReader};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "usage: lsm-cli <data-dir> <command> [arguments]
+
+commands:
+    get <key>                 print the value of <key>
+    put <key> <value>         set <key> to <value>
+    delete <key>              delete <key>
+    scan [start] [end]        print every key in start..end, and its value
+    dump-wal [file]           print every record of the WAL, or of the given log file
+    dump-segment [file]       print every entry of every segment, or of the given segment file
+    stats                     print the shape of the engine
+    compact                   flush the memtable and merge every segment
+    verify                    check every file against the manifest: readable, sorted and matching its checksum
+    repair                    rebuild the directory from whatever can still be read, printing what was lost";
+
+type CliResult = Result<bool, Box<dyn Error>>;
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let arguments = args[2..].iter().map(String::as_str).collect::<Vec<_>>();
+    match run(Path::new(&args[0]), &args[1], &arguments) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs `command`, returning whether it succeeded.
+fn run(dir: &Path, command: &str, arguments: &[&str]) -> CliResult {
+    match (command, arguments) {
+        ("get", [key]) => match open_existing(dir)?.read(key)? {
+            Some(value) => println!("{}", value),
+            None => {
+                eprintln!("{} not found", key);
+                return Ok(false);
+            }
+        },
+        ("put", [key, value]) => LSMEngine::open(dir)?.write(key.to_string(), value.to_string())?,
+        ("delete", [key]) => open_existing(dir)?.delete(key)?,
+        ("scan", range) if range.len() <= 2 => {
+            let start = range.first().copied().unwrap_or("");
+            let end = range.get(1).copied().unwrap_or("\u{10ffff}");
+            for (key, value) in open_existing(dir)?.scan(start, end)? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        ("dump-wal", file) if file.len() <= 1 => {
+            for path in files(dir, file.first(), "log")? {
+                println!("# {}", path.display());
+                for record in WalReader::open(&path)? {
+                    println!("{}", describe(&record?));
+                }
+            }
+        }
+        ("dump-segment", file) if file.len() <= 1 => {
+            for path in files(dir, file.first(), "sst")? {
+                println!("# {}", path.display());
+                for entry in SegmentReader::open(&path)? {
+                    let (offset, record) = entry?;
+                    println!("{}\t{}", offset, describe(&record));
+                }
+            }
+        }
+        ("stats", []) => stats(&open_existing(dir)?)?,
+        ("compact", []) => open_existing(dir)?.compact()?,
+        ("verify", []) => return verify(dir),
+        ("repair", []) => {
+            let report = LSMEngine::repair(dir)?;
+            if report.manifest_lost {
+                println!("the manifest was lost: every segment went into level 0, and pending range deletions are gone");
+            }
+            println!("kept {} entries in {} segments, and {} WAL records", report.entries, report.segments, report.records);
+            println!("WAL records are numbered from {} on: followers and subscribers must start over", report.first_sequence);
+            for problem in report.lost.iter() {
+                println!("lost {}", problem);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(false);
+        }
+    }
+    return Ok(true);
+}
+
+/// Opens the engine in `dir`, which must be a data directory already, so that a mistyped path doesn't leave an empty
+/// one behind.
+fn open_existing(dir: &Path) -> Result<LSMEngine, Box<dyn Error>> {
+    if !dir.join("MANIFEST").is_file() {
+        return Err(format!("{} is not a data directory: it has no MANIFEST", dir.display()).into());
+    }
+    return Ok(LSMEngine::open(dir)?);
+}
+
+/// The file given on the command line, or else every file in `dir` with the extension.
+fn files(dir: &Path, file: Option<&&str>, extension: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if let Some(file) = file {
+        return Ok(vec![dir.join(file)]);
+    }
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|found| found == extension) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    return Ok(paths);
+}
+
+fn describe(record: &Record) -> String {
+    return match record {
+        Record::Put { key, value } => format!("put {:?} {:?}", key, value),
+        Record::Delete { key } => format!("delete {:?}", key),
+        Record::Merge { key, operands } => format!("merge {:?} {:?}", key, operands),
+        Record::DeleteRange { start, end } => format!("delete-range {:?} {:?}", start, end),
+        Record::Batch(records) => format!("batch [{}]", records.iter().map(describe).collect::<Vec<_>>().join(", ")),
+        Record::Ingest { files } => format!("ingest {:?}", files),
+    };
+}
+
+fn stats(lsm: &LSMEngine) -> Result<(), Box<dyn Error>> {
+    let properties = [
+        "lsm.num-levels",
+        "lsm.num-segments",
+        "lsm.total-segment-bytes",
+        "lsm.num-entries-segments",
+        "lsm.num-tombstones",
+        "lsm.num-range-tombstones",
+        "lsm.num-entries-memtable",
+        "lsm.sparse-index-entries",
+        "lsm.pending-compaction-bytes",
+    ];
+    for name in properties {
+        println!("{}: {}", name, lsm.get_property(name)?.unwrap_or_default());
+    }
+    print!("{}", lsm.get_property("lsm.levels")?.unwrap_or_default());
+    return Ok(());
+}
+
+/// Checks the data directory without opening it, printing every problem found.
+fn verify(dir: &Path) -> CliResult {
+    let problems = verify_dir(dir)?;
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("ok");
+    }
+    return Ok(problems.is_empty());
+}
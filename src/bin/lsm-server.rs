@@ -0,0 +1,29 @@
+//! Serves a data directory over TCP, so that several processes can share it:
+//!
+//! `lsm-server <data-dir> [address]`
+//!
+//! The address defaults to 127.0.0.1:6380, one port up from Redis's. See `lsm_engine::Server` for the commands.
+
+// explicit returns are the house style
+#![allow(clippy::needless_return)]
+
+use lsm_engine::{ConcurrentEngine, LSMEngine, Server};
+use std::process;
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args.len() > 2 {
+        eprintln!("usage: lsm-server <data-dir> [address]");
+        process::exit(2);
+    }
+    let addr = args.get(1).map_or("127.0.0.1:6380", String::as_str);
+    let served = LSMEngine::open(&args[0]).and_then(|engine| Server::bind(addr, ConcurrentEngine::new(engine)));
+    let result = served.and_then(|server| {
+        println!("serving {} on {}", args[0], server.local_addr()?);
+        return server.run();
+    });
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        process::exit(1);
+    }
+}
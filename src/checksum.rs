@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// A 64-bit FNV-1a hash: not cryptographic, but cheap to compute as the bytes stream by, and stable across builds
+/// and platforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Checksum(u64);
+
+impl Default for Checksum {
+    fn default() -> Self {
+        return Checksum(0xcbf2_9ce4_8422_2325);
+    }
+}
+
+impl Checksum {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Carries on from a checksum computed earlier.
+    pub fn resume(value: u64) -> Self {
+        return Checksum(value);
+    }
+
+    pub fn value(&self) -> u64 {
+        return self.0;
+    }
+
+    pub fn of_file(path: &Path) -> io::Result<u64> {
+        let mut checksum = Checksum::default();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buffer = [0; 8192];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(checksum.value());
+            }
+            checksum.update(&buffer[..read]);
+        }
+    }
+}
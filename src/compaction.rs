@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// What a [`CompactionFilter`] wants done with an entry that survived a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Keep,
+    Remove,
+    ChangeValue(String),
+}
+
+/// A hook invoked on every live entry rewritten by a compaction, allowing stale records to be
+/// garbage-collected (or rewritten) without issuing explicit deletes.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &str, value: &str) -> Decision;
+}
+
+/// Passes entries on to a filter, remembering the keys it removed or changed.
+pub(crate) struct ChangeRecorder {
+    filter: Arc<dyn CompactionFilter>,
+    changed: Mutex<Vec<String>>,
+}
+
+impl ChangeRecorder {
+    pub fn new(filter: Arc<dyn CompactionFilter>) -> Self {
+        return ChangeRecorder { filter, changed: Mutex::new(vec![]) };
+    }
+
+    pub fn into_changed(self) -> Vec<String> {
+        return self.changed.into_inner().unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+impl CompactionFilter for ChangeRecorder {
+    fn filter(&self, key: &str, value: &str) -> Decision {
+        let decision = self.filter.filter(key, value);
+        if decision != Decision::Keep {
+            self.changed.lock().unwrap_or_else(PoisonError::into_inner).push(key.to_owned());
+        }
+        return decision;
+    }
+}
+
+/// Per-run statistics of a compaction, including what the compaction filter did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub entries_kept: usize,
+    pub entries_removed: usize,
+    pub entries_changed: usize,
+    pub bytes_written: u64,
+}
+
+impl CompactionStats {
+    pub(crate) fn record(&mut self, decision: &Decision) {
+        match decision {
+            Decision::Keep => self.entries_kept += 1,
+            Decision::Remove => self.entries_removed += 1,
+            Decision::ChangeValue(_) => self.entries_changed += 1,
+        }
+    }
+}
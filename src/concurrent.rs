@@ -0,0 +1,436 @@
+use crate::{Error, LSMEngine, Result, Stats, Subscription, WriteBatch};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+
+type Ticket = u64;
+
+#[derive(Default)]
+struct Queue {
+    next_ticket: Ticket,
+    pending: VecDeque<(Ticket, WriteBatch)>,
+    //whether a group is being committed; the next one only starts once it is done, so groups never overlap
+    leading: bool,
+    //batches of the group in flight, waiting for their own writers to insert them
+    inserting: HashMap<Ticket, WriteBatch>,
+    inserts_left: usize,
+    group_keys: Vec<String>,
+    group_tickets: Vec<Ticket>,
+    //writes whose group is done, along with the error message if it failed
+    done: HashMap<Ticket, Option<String>>,
+}
+
+struct Shared {
+    engine: RwLock<LSMEngine>,
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+/// A handle to an [`LSMEngine`] that many threads can write through at once.
+///
+/// Writers queue up, and the first in line commits everyone queued behind it as one group: a single WAL append (and,
+/// with [`wal_sync`](crate::LSMBuilder::wal_sync), a single flush to disk) covers the whole group. If the memtable
+/// allows it, like [`SkipListRep`](crate::SkipListRep), every writer then inserts its own batch concurrently.
+/// Reads take the engine exclusively, and compactions and checkpoints also wait for the group in flight to be inserted.
+/// A group delayed by a write slowdown sleeps without holding the engine.
+///
+/// Clones share the same engine.
+#[derive(Clone)]
+pub struct ConcurrentEngine {
+    shared: Arc<Shared>,
+}
+
+impl ConcurrentEngine {
+    pub fn new(engine: LSMEngine) -> Self {
+        let shared = Shared { engine: RwLock::new(engine), queue: Mutex::new(Queue::default()), changed: Condvar::new() };
+        return ConcurrentEngine { shared: Arc::new(shared) };
+    }
+
+    pub fn write(&self, key: String, value: String) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        return self.write_batch(batch);
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        return self.write_batch(batch);
+    }
+
+    /// Applies every write in `batch` atomically, possibly committed in a group with other threads' writes.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        batch.check_values()?;
+        let mut queue = self.queue();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push_back((ticket, batch));
+        loop {
+            if let Some(outcome) = queue.done.remove(&ticket) {
+                return match outcome {
+                    None => Ok(()),
+                    Some(message) => Err(Error::WriteGroupFailed { message }),
+                };
+            }
+            if let Some(batch) = queue.inserting.remove(&ticket) {
+                drop(queue);
+                let abandon = Abandon { handle: self, own: ticket, tickets: vec![] };
+                self.engine_shared(|engine| engine.insert_concurrently(batch));
+                drop(abandon);
+                queue = self.queue();
+                //another writer of the group panicked meanwhile, and its failure is already recorded
+                if !queue.group_tickets.contains(&ticket) {
+                    continue;
+                }
+                queue.inserts_left -= 1;
+                if queue.inserts_left == 0 {
+                    queue = self.finish_group(queue);
+                }
+                continue;
+            }
+            if !queue.leading {
+                queue.leading = true;
+                if let Some(error) = self.lead(queue, ticket) {
+                    return Err(error);
+                }
+                queue = self.queue();
+                continue;
+            }
+            queue = self.shared.changed.wait(queue).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Commits everything queued as one group. Returns the leader's own error, if the group failed.
+    fn lead(&self, mut queue: MutexGuard<'_, Queue>, leader: Ticket) -> Option<Error> {
+        let (tickets, group): (Vec<_>, Vec<_>) = queue.pending.drain(..).unzip();
+        drop(queue);
+
+        let abandon = Abandon { handle: self, own: leader, tickets: tickets.clone() };
+        let committed = self.engine_exclusive(|engine| engine.commit_group(group));
+        drop(abandon);
+        //a delayed group is held back without the engine, so reads go on meanwhile
+        let committed = committed.map(|(group, delay)| {
+            thread::sleep(delay);
+            group
+        });
+        let mut queue = self.queue();
+        match committed {
+            Ok(Some(group)) => {
+                queue.group_keys = group.iter().flat_map(|batch| batch.entries.iter()).map(|kv| kv.key.clone()).collect();
+                queue.inserts_left = group.len();
+                queue.inserting = tickets.iter().copied().zip(group).collect();
+                queue.group_tickets = tickets;
+                self.shared.changed.notify_all();
+                return None;
+            }
+            Ok(None) => {
+                queue.done.extend(tickets.into_iter().map(|ticket| (ticket, None)));
+            }
+            Err(error) => {
+                let message = error.to_string();
+                queue.done.extend(tickets.into_iter().filter(|ticket| *ticket != leader).map(|ticket| (ticket, Some(message.clone()))));
+                queue.leading = false;
+                self.shared.changed.notify_all();
+                return Some(error);
+            }
+        }
+        queue.leading = false;
+        self.shared.changed.notify_all();
+        return None;
+    }
+
+    /// Run by the last writer of a group to finish inserting: completes the group and lets the next one start.
+    fn finish_group<'a>(&self, mut queue: MutexGuard<'a, Queue>) -> MutexGuard<'a, Queue> {
+        let keys = std::mem::take(&mut queue.group_keys);
+        self.engine_exclusive(|engine| engine.finish_group(&keys));
+        let tickets = std::mem::take(&mut queue.group_tickets);
+        queue.done.extend(tickets.into_iter().map(|ticket| (ticket, None)));
+        queue.leading = false;
+        self.shared.changed.notify_all();
+        return queue;
+    }
+
+    pub fn read(&self, key: &str) -> Result<Option<String>> {
+        return self.engine_exclusive(|engine| engine.read(key));
+    }
+
+    pub fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        return self.engine_exclusive(|engine| engine.multi_get(keys));
+    }
+
+    pub fn scan(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        return self.engine_exclusive(|engine| engine.scan(start, end));
+    }
+
+    pub fn stats(&self) -> Stats {
+        return self.engine_shared(|engine| engine.stats());
+    }
+
+    /// See [`LSMEngine::latest_sequence`].
+    pub fn latest_sequence(&self) -> u64 {
+        return self.engine_shared(|engine| engine.latest_sequence());
+    }
+
+    /// See [`LSMEngine::subscribe`].
+    pub fn subscribe(&self, from_seq: u64) -> Result<Subscription> {
+        return self.engine_shared(|engine| engine.subscribe(from_seq));
+    }
+
+    /// See [`LSMEngine::compact`].
+    pub fn compact(&self) -> Result<()> {
+        return self.engine_between_groups(|engine| engine.compact());
+    }
+
+    /// See [`LSMEngine::checkpoint`]. Writes wait until it is done, which in a data directory only takes a flush and
+    /// a few hard links.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        return self.engine_between_groups(|engine| engine.checkpoint(dir));
+    }
+
+    /// Hands back the engine, once every other clone of the handle is gone.
+    pub fn into_inner(self) -> Option<LSMEngine> {
+        let shared = Arc::try_unwrap(self.shared).ok()?;
+        return Some(shared.engine.into_inner().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        return self.shared.queue.lock().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    fn engine_shared<T>(&self, f: impl FnOnce(&LSMEngine) -> T) -> T {
+        return f(&self.shared.engine.read().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    fn engine_exclusive<T>(&self, f: impl FnOnce(&mut LSMEngine) -> T) -> T {
+        return f(&mut self.shared.engine.write().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Takes the engine exclusively once no group is in flight: a flush between a group being logged and its writers
+    /// inserting it would leave the group's records out of both the new WAL and the flushed segment.
+    fn engine_between_groups<T>(&self, f: impl FnOnce(&mut LSMEngine) -> T) -> T {
+        let mut queue = self.queue();
+        while queue.leading {
+            queue = self.shared.changed.wait(queue).unwrap_or_else(PoisonError::into_inner);
+        }
+        //the next group can only start once the engine is free again
+        let mut engine = self.shared.engine.write().unwrap_or_else(PoisonError::into_inner);
+        drop(queue);
+        return f(&mut engine);
+    }
+}
+
+/// Fails the group in flight if the writer holding it panics, so that the writers queued behind it aren't left waiting
+/// for a group that never finishes.
+struct Abandon<'a> {
+    handle: &'a ConcurrentEngine,
+    //the panicking writer's own ticket, which it never comes back for
+    own: Ticket,
+    //the group's tickets, unless they are in the queue already
+    tickets: Vec<Ticket>,
+}
+
+impl Drop for Abandon<'_> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        let mut queue = self.handle.queue();
+        let tickets = std::mem::take(&mut self.tickets).into_iter().chain(std::mem::take(&mut queue.group_tickets));
+        let message = "a writer panicked while its group was committed".to_owned();
+        let failed = tickets.filter(|ticket| *ticket != self.own).map(|ticket| (ticket, Some(message.clone()))).collect::<Vec<_>>();
+        queue.done.extend(failed);
+        queue.inserting.clear();
+        queue.inserts_left = 0;
+        queue.group_keys.clear();
+        queue.leading = false;
+        self.handle.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConcurrentMemtableRep, LSMBuilder, MemtableRep, SkipListRep};
+    use std::time::Duration;
+
+    fn hammer(lsm: &ConcurrentEngine, threads: usize, writes: usize) {
+        let handles = (0..threads)
+            .map(|thread| {
+                let lsm = lsm.clone();
+                thread::spawn(move || {
+                    for i in 0..writes {
+                        lsm.write(format!("t{}-k{:03}", thread, i), i.to_string()).unwrap();
+                        //every thread also fights over the same key
+                        lsm.write("shared".to_owned(), format!("{}-{}", thread, i)).unwrap();
+                    }
+                    lsm.delete(&format!("t{}-k000", thread)).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_writers() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let wal = tempfile::NamedTempFile::new()?;
+        let engine = LSMBuilder::new()
+            .segment_size(100)
+            .inmemory_capacity(64)
+            .memtable_rep(SkipListRep::default())
+            .wal_path(wal.path())
+            .build();
+        let lsm = ConcurrentEngine::new(engine);
+        hammer(&lsm, 8, 50);
+
+        let scanned = lsm.scan("t", "u")?;
+        assert_eq!(scanned.len(), 8 * 49);
+        assert_eq!(lsm.read("t3-k049")?, Some("49".to_owned()));
+        assert_eq!(lsm.read("t3-k000")?, None);
+        assert!(lsm.read("shared")?.unwrap().ends_with("-49"));
+        let stats = lsm.stats();
+        assert_eq!(stats.writes, 8 * 101);
+        assert!(stats.write_groups <= stats.writes);
+
+        let mut recovered = LSMBuilder::new().build();
+        recovered.recover_from(std::fs::File::open(wal.path())?)?;
+        assert_eq!(recovered.scan("t", "u")?, scanned);
+        assert_eq!(recovered.read("shared")?, lsm.read("shared")?);
+        Ok(())
+    }
+
+    //a skip list slow enough to insert into that other things happen while a group is in flight, and that panics on
+    //the key "panic"
+    #[derive(Default)]
+    struct SlowRep(SkipListRep);
+
+    impl MemtableRep for SlowRep {
+        fn insert(&mut self, key: String, value: String) -> Option<(String, String)> {
+            return self.0.insert(key, value);
+        }
+
+        fn get(&self, key: &str) -> Option<String> {
+            return self.0.get(key);
+        }
+
+        fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = (String, String)> + 'a> {
+            return self.0.range(start, end);
+        }
+
+        fn remove_range(&mut self, start: &str, end: &str) -> Vec<(String, String)> {
+            return self.0.remove_range(start, end);
+        }
+
+        fn drain(&mut self) -> Box<dyn Iterator<Item = (String, String)>> {
+            return self.0.drain();
+        }
+
+        fn len(&self) -> usize {
+            return self.0.len();
+        }
+
+        fn as_concurrent(&self) -> Option<&dyn ConcurrentMemtableRep> {
+            return Some(self);
+        }
+
+        fn entry_overhead(&self) -> usize {
+            return self.0.entry_overhead();
+        }
+    }
+
+    impl ConcurrentMemtableRep for SlowRep {
+        fn insert_concurrently(&self, key: String, value: String) -> Option<(String, String)> {
+            assert_ne!(key, "panic");
+            thread::sleep(Duration::from_millis(5));
+            return self.0.insert_concurrently(key, value);
+        }
+    }
+
+    #[test]
+    fn test_compact_between_groups() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (dir, checkpoints) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let builder = || LSMBuilder::new().segment_size(1000).inmemory_capacity(1000).wal_retention(1000).memtable_rep(SlowRep::default());
+        let lsm = ConcurrentEngine::new(builder().open(dir.path())?);
+        let compactor = {
+            let (lsm, checkpoints) = (lsm.clone(), checkpoints.path().to_owned());
+            thread::spawn(move || -> Result<()> {
+                for i in 0..20 {
+                    lsm.compact()?;
+                    lsm.checkpoint(checkpoints.join(i.to_string()))?;
+                }
+                return Ok(());
+            })
+        };
+        hammer(&lsm, 8, 40);
+        compactor.join().unwrap()?;
+        let mut lsm = lsm.into_inner().unwrap();
+
+        //a checkpoint holds exactly the records up to its sequence number, group or no group in flight
+        for i in 0..20 {
+            let mut checkpoint = builder().open(checkpoints.path().join(i.to_string()))?;
+            let mut expected = std::collections::BTreeMap::new();
+            for entry in lsm.wal_iterator(0)?.take(checkpoint.latest_sequence() as usize) {
+                match entry?.1 {
+                    crate::Record::Put { key, value } => expected.insert(key, value),
+                    crate::Record::Delete { key } => expected.remove(&key),
+                    other => unreachable!("only puts and deletes were written, not {:?}", other),
+                };
+            }
+            assert_eq!(checkpoint.scan("", "~")?, expected.into_iter().collect::<Vec<_>>(), "checkpoint {}", i);
+        }
+        let scanned = lsm.scan("", "~")?;
+        drop(lsm);
+        assert_eq!(builder().open(dir.path())?.scan("", "~")?, scanned);
+        Ok(())
+    }
+
+    #[test]
+    fn test_panicking_writer() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let lsm = ConcurrentEngine::new(LSMBuilder::new().segment_size(100).inmemory_capacity(100).memtable_rep(SlowRep::default()).build());
+        let panicking = lsm.clone();
+        assert!(thread::spawn(move || panicking.write("panic".to_owned(), "v".to_owned())).join().is_err());
+        //the writers after it aren't stuck behind its group
+        hammer(&lsm, 4, 10);
+        assert_eq!(lsm.scan("t", "u")?.len(), 4 * 9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delayed_group() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let engine = LSMBuilder::new()
+            .segment_size(10)
+            .inmemory_capacity(2)
+            .compaction_trigger(10)
+            .slowdown_writes_trigger(1)
+            .delayed_write_rate(20)
+            .build();
+        let lsm = ConcurrentEngine::new(engine);
+        for i in 0..3 {
+            lsm.write(format!("k{}", i), i.to_string())?;
+        }
+        //k2 flushed a segment, so a write of 20 bytes is now held back for a second
+        let writer = lsm.clone();
+        let delayed = thread::spawn(move || writer.write("k3".to_owned(), "v".repeat(18)));
+        thread::sleep(Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        assert_eq!(lsm.read("k0")?, Some("0".to_owned()));
+        assert!(started.elapsed() < Duration::from_millis(500));
+        delayed.join().unwrap()?;
+        assert_eq!(lsm.read("k3")?, Some("v".repeat(18)));
+        assert_eq!(lsm.stats().write_slowdowns, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequential_memtable() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        //the default b-tree can't take concurrent inserts, so groups are applied one write at a time
+        let lsm = ConcurrentEngine::new(LSMBuilder::new().segment_size(50).inmemory_capacity(20).build());
+        hammer(&lsm, 4, 30);
+        assert_eq!(lsm.scan("t", "u")?.len(), 4 * 29);
+        let engine = lsm.into_inner().unwrap();
+        assert!(engine.stats().memtable_flushes > 0);
+        Ok(())
+    }
+}
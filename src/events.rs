@@ -0,0 +1,42 @@
+use crate::{CompactionStats, Error, SegmentDescription};
+use std::time::Duration;
+
+/// Describes a finished memtable flush.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushInfo {
+    pub entries: usize,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// Describes a finished compaction: the segments that went in, the ones that came out and what it did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionInfo {
+    pub input: Vec<SegmentDescription>,
+    pub output: Vec<SegmentDescription>,
+    pub stats: CompactionStats,
+    pub duration: Duration,
+}
+
+/// Callbacks for the engine's lifecycle events, registered with
+/// [`LSMBuilder::event_listener`](crate::LSMBuilder::event_listener).
+///
+/// Callbacks run synchronously on the thread performing the write that triggered them, so they should be quick.
+/// Every method has an empty default implementation.
+pub trait EventListener: Send + Sync {
+    /// Called before the memtable, holding `entries` entries, is written out to a new segment.
+    fn on_flush_begin(&self, _entries: usize) {}
+
+    fn on_flush_completed(&self, _info: &FlushInfo) {}
+
+    /// Called before `input` is merged into a fresh sorted run.
+    fn on_compaction_begin(&self, _input: &[SegmentDescription]) {}
+
+    fn on_compaction_completed(&self, _info: &CompactionInfo) {}
+
+    /// Called whenever the engine starts logging to a different WAL file.
+    fn on_wal_rotated(&self) {}
+
+    /// Called when a flush or compaction fails, before the error is returned to the caller.
+    fn on_background_error(&self, _error: &Error) {}
+}
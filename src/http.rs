@@ -0,0 +1,299 @@
+use crate::kv::KVPair;
+use crate::net::{Listener, Protocol};
+use crate::{ConcurrentEngine, Error, Result};
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The largest request body read; anything longer is refused with 413 before it's read.
+const MAX_BODY: usize = 16 << 20;
+/// How long a connection may go without sending anything before its request is given up on.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    //JSON, if any
+    body: Option<String>,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Result<Response> {
+        return Ok(Response { status, body: Some(serde_json::to_string(body)?) });
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Result<Response> {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+        return Response::json(status, &Body { error: message.into() });
+    }
+
+    fn empty() -> Result<Response> {
+        return Ok(Response { status: 204, body: None });
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    return match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn decode(encoded: &str, plus_is_space: bool) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b'+' if plus_is_space => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+    return String::from_utf8(bytes).ok();
+}
+
+fn bad_request<T>(reason: impl Into<String>) -> Result<T> {
+    return Err(Error::ProtocolError { reason: reason.into() });
+}
+
+/// Reads a request up to its body, which is left unread, and says how long the body is; none if the client hung up
+/// before sending one.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<(Request, usize)>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next(), words.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method.to_owned(), target.to_owned()),
+        _ => return bad_request(format!("malformed request line {:?}", line.trim_end())),
+    };
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return bad_request("the request ended halfway through its headers");
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().or_else(|_| bad_request(format!("bad content length {:?}", value.trim())))?;
+            }
+        }
+    }
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let mut pairs = vec![];
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match (decode(name, true), decode(value, true)) {
+            (Some(name), Some(value)) => pairs.push((name, value)),
+            _ => return bad_request(format!("malformed query {:?}", query)),
+        }
+    }
+    return Ok(Some((Request { method, path: path.to_owned(), query: pairs, body: vec![] }, length)));
+}
+
+fn route(engine: &ConcurrentEngine, request: &Request) -> Result<Response> {
+    if let Some(key) = request.path.strip_prefix("/kv/") {
+        let key = match decode(key, false) {
+            Some(key) if !key.is_empty() => key,
+            _ => return Response::error(400, "the key must be non-empty, percent-encoded UTF-8"),
+        };
+        return match request.method.as_str() {
+            "GET" => match engine.read(&key)? {
+                Some(value) => Response::json(200, &KVPair { key, value }),
+                None => Response::error(404, format!("{} not found", key)),
+            },
+            "PUT" => {
+                let kv = match serde_json::from_slice::<KVPair>(&request.body) {
+                    Ok(kv) => kv,
+                    Err(error) => return Response::error(400, format!("the body must be {{\"key\": ..., \"value\": ...}}: {}", error)),
+                };
+                if kv.key != key {
+                    return Response::error(400, format!("the body's key {} does not match the path's {}", kv.key, key));
+                }
+                match engine.write(kv.key, kv.value) {
+                    Err(error @ Error::ReservedValue { .. }) => return Response::error(400, error.to_string()),
+                    result => result?,
+                }
+                Response::empty()
+            }
+            "DELETE" => {
+                engine.delete(&key)?;
+                Response::empty()
+            }
+            _ => Response::error(405, "use GET, PUT or DELETE"),
+        };
+    }
+    let parameter = |name: &str| request.query.iter().find(|(found, _value)| found == name).map(|(_name, value)| value.as_str());
+    return match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/scan") => {
+            let (start, end) = (parameter("start").unwrap_or(""), parameter("end").unwrap_or("\u{10ffff}"));
+            let pairs = engine.scan(start, end)?.into_iter().map(|(key, value)| KVPair { key, value }).collect::<Vec<_>>();
+            Response::json(200, &pairs)
+        }
+        ("GET", "/stats") => Response::json(200, &engine.stats()),
+        ("POST", "/compact") => {
+            engine.compact()?;
+            Response::empty()
+        }
+        (_, "/scan") | (_, "/stats") | (_, "/compact") => Response::error(405, "wrong method"),
+        _ => Response::error(404, format!("no such endpoint {}", request.path)),
+    };
+}
+
+fn respond(stream: &mut TcpStream, response: &Response) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\nConnection: close\r\n", response.status, reason(response.status));
+    let body = response.body.as_deref().unwrap_or("");
+    if response.body.is_some() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    return Ok(());
+}
+
+//one request per connection, which keeps clients honest about framing
+fn serve_connection(engine: &ConcurrentEngine, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let response = match read_request(&mut reader) {
+        Ok(None) => return Ok(()),
+        Ok(Some((_request, length))) if length > MAX_BODY => {
+            Response::error(413, format!("request bodies are at most {} bytes long", MAX_BODY))?
+        }
+        Ok(Some((mut request, length))) => {
+            request.body = vec![0; length];
+            match reader.read_exact(&mut request.body) {
+                Ok(()) => route(engine, &request).or_else(|error| Response::error(500, error.to_string()))?,
+                Err(error) => Response::error(400, error.to_string())?,
+            }
+        }
+        Err(error) => Response::error(400, error.to_string())?,
+    };
+    return respond(&mut writer, &response);
+}
+
+/// Serves an engine over HTTP, with JSON bodies in the shape of `KVPair`:
+///
+/// - `GET /kv/{key}` replies with `{"key": ..., "value": ...}`, or 404 if the key isn't there
+/// - `PUT /kv/{key}` takes `{"key": ..., "value": ...}`, with the same key as the path
+/// - `DELETE /kv/{key}`
+/// - `GET /scan?start=...&end=...` replies with every pair in `start..end`; both ends are optional
+/// - `GET /stats` replies with the engine's [`Stats`](crate::Stats)
+/// - `POST /compact`
+///
+/// Keys in the path are percent-encoded. Errors come back as `{"error": ...}`. Bodies longer than 16 MiB are refused
+/// with 413, and a client that goes quiet for 30 seconds halfway through its request is hung up on.
+pub type HttpServer = Listener<Http>;
+
+/// HTTP, as an [`HttpServer`] speaks it.
+pub struct Http;
+
+impl Protocol for Http {
+    type Shared = ConcurrentEngine;
+
+    fn serve(engine: &ConcurrentEngine, stream: TcpStream) -> Result<()> {
+        return serve_connection(engine, stream);
+    }
+}
+
+impl HttpServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: ConcurrentEngine) -> Result<Self> {
+        return Listener::bind_to(addr, engine);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LSMBuilder;
+    use std::net::SocketAddr;
+
+    fn request(addr: SocketAddr, method: &str, target: &str, body: &str) -> Result<(u16, String)> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+        return Ok((status, body));
+    }
+
+    #[test]
+    fn test_endpoints() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let engine = ConcurrentEngine::new(LSMBuilder::new().segment_size(5).inmemory_capacity(5).build());
+        let server = HttpServer::bind("127.0.0.1:0", engine)?.spawn()?;
+        let addr = server.addr();
+
+        for i in 0..10 {
+            let body = format!(r#"{{"key":"k{}","value":"v{}"}}"#, i, i);
+            assert_eq!(request(addr, "PUT", &format!("/kv/k{}", i), &body)?, (204, "".to_owned()));
+        }
+        assert_eq!(request(addr, "GET", "/kv/k3", "")?, (200, r#"{"key":"k3","value":"v3"}"#.to_owned()));
+        assert_eq!(request(addr, "PUT", "/kv/a%20b", r#"{"key":"a b","value":"spaced"}"#)?.0, 204);
+        assert_eq!(request(addr, "GET", "/kv/a%20b", "")?, (200, r#"{"key":"a b","value":"spaced"}"#.to_owned()));
+        assert_eq!(request(addr, "DELETE", "/kv/k3", "")?.0, 204);
+        assert_eq!(request(addr, "GET", "/kv/k3", "")?.0, 404);
+
+        let (status, body) = request(addr, "GET", "/scan?start=k1&end=k5", "")?;
+        assert_eq!(status, 200);
+        let pairs: Vec<KVPair> = serde_json::from_str(&body)?;
+        assert_eq!(pairs.iter().map(|kv| kv.key.as_str()).collect::<Vec<_>>(), vec!["k1", "k2", "k4"]);
+        let (_status, body) = request(addr, "GET", "/scan", "")?;
+        assert_eq!(serde_json::from_str::<Vec<KVPair>>(&body)?.len(), 10);
+
+        assert_eq!(request(addr, "POST", "/compact", "")?.0, 204);
+        let (status, body) = request(addr, "GET", "/stats", "")?;
+        assert_eq!(status, 200);
+        let stats: serde_json::Value = serde_json::from_str(&body)?;
+        assert_eq!(stats["writes"], 12);
+        assert!(stats["compactions"].as_u64() >= Some(1));
+
+        //mistakes
+        assert_eq!(request(addr, "PUT", "/kv/k1", r#"{"key":"k2","value":"v"}"#)?.0, 400);
+        assert_eq!(request(addr, "PUT", "/kv/k1", "not json")?.0, 400);
+        let forged = serde_json::to_string(&KVPair { key: "k1".to_owned(), value: crate::WriteBatch::new().encode() })?;
+        assert_eq!(request(addr, "PUT", "/kv/k1", &forged)?.0, 400);
+        assert_eq!(request(addr, "POST", "/kv/k1", "")?.0, 405);
+        assert_eq!(request(addr, "GET", "/compact", "")?.0, 405);
+        let (status, body) = request(addr, "GET", "/nowhere", "")?;
+        assert_eq!(status, 404);
+        assert!(body.starts_with(r#"{"error":"#));
+
+        //an oversized body is refused without waiting for it
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "PUT /kv/big HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        server.shutdown()?;
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
 use thiserror::Error;
+use crate::checksum::Checksum;
 
 pub(crate) type Result<T> = std::result::Result<T, KvError>;
 
@@ -49,6 +50,7 @@ pub trait KVFileIterator {
 }
 
 pub trait KVFileReader: KVFileIterator {
+    #[allow(dead_code)]
     fn read(&mut self) -> Box<dyn Iterator<Item = Result<KVPair>> + '_> {
         let reader = BufReader::new(self.file_as_mut());
 
@@ -64,11 +66,50 @@ pub trait KVFileReader: KVFileIterator {
     }
 }
 
-pub trait KVFileWriter: KVFileIterator {
-    fn persist(&mut self, kv: KVPair) -> Result<u64> {
-        let current_offset = self.tell()?;
-        serde_json::to_writer(self.file_as_mut(), &kv)?;
-        self.file_as_mut().write(b"\n")?;
-        return Ok(current_offset);
+/// Reads a file of JSON lines one entry at a time, keeping track of where each one starts and of a checksum of
+/// every byte read so far. A line that can't be parsed is reported and skipped over.
+pub(crate) struct LineReader<R> {
+    reader: R,
+    offset: u64,
+    checksum: Checksum,
+    failed: bool,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        return LineReader { reader, offset: 0, checksum: Checksum::default(), failed: false };
+    }
+
+    /// The offset of the next entry.
+    pub fn offset(&self) -> u64 {
+        return self.offset;
+    }
+
+    pub fn checksum(&self) -> u64 {
+        return self.checksum.value();
+    }
+}
+
+impl<R: BufRead> Iterator for LineReader<R> {
+    type Item = (u64, Result<KVPair>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let offset = self.offset;
+        let mut line = vec![];
+        match self.reader.read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(length) => self.offset += length as u64,
+            Err(error) => {
+                //the rest of the file is out of reach
+                self.failed = true;
+                return Some((offset, Err(error.into())));
+            }
+        }
+        self.checksum.update(&line);
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        return Some((offset, serde_json::from_slice::<KVPair>(line).map_err(KvError::from)));
     }
 }
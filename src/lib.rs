@@ -51,33 +51,108 @@
 //! ### Write
 //! When a write comes in, the following happens:
 //! * The entry is written into the WAL file (unless an explicit request is made not to)
-//! * If the size of the internal is at full capacity, the contents are dumped into a segment file, with compaction performed in the end.
+//! * If the internal memtable is at full capacity, the contents are dumped into a segment file, with compaction performed in the end.
+//!   The memtable is full once it holds `inmemory_capacity` entries or, if set, `write_buffer_size` bytes, or when the
+//!   memory budget of a shared [`WriteBufferManager`] runs out.
+//!   The memtable is a B-tree by default; [`LSMBuilder::memtable_rep`] swaps in another [`MemtableRep`].
 //! * The entry is then inserted into the now-empty memtable.
 //!
 //! ### Read
 //! When a request for a read is made, the following happens:
 //! * It first checks its internal memtable for the value corresponding to the requested key. If it exists, it returns the value
 //! * Otherwise, it looks up the offset of the closest key with its sparse memory index. This is a balanced tree that maintains
-//! the position of 1 out of every `sparse_offset` entries in memeory.
+//!   the position of 1 out of every `sparse_offset` entries in memeory.
 //! * It then linearly scans forward from that offset, looking for the desired key-value entry.
 //!
 //! ### Delete
 //! This is just a special case of write, with value being a special tombstone string.
+//! Whole key ranges can be deleted with a single range tombstone through [`LSMEngine::delete_range`]; the tombstone
+//! hides every older entry in the range from reads and scans until the next compaction physically drops them.
+//!
+//! ### Merge
+//! Read-modify-write updates (counters, append-only lists, ...) can skip the read entirely with a [`MergeOperator`]
+//! registered on the builder. [`LSMEngine::merge`] only records the operand; operands are combined with the existing
+//! value when the key is read, and folded into a single value when segments are compacted.
+//!
+//! ### Transactions
+//! [`LSMEngine::begin_transaction`] starts an optimistic [`Transaction`]: writes are buffered and validated at commit,
+//! failing if a key it touched changed in the meantime. Under high contention,
+//! [`LSMEngine::begin_pessimistic_transaction`] locks keys up front instead. Both commit through a single
+//! [`WriteBatch`] record in the WAL.
+//!
+//! ### Concurrent writes
+//! [`ConcurrentEngine`] lets many threads write at once: queued writes are committed in groups sharing a single WAL
+//! append, and with a [`SkipListRep`] memtable each writer inserts its own entries concurrently. See the
+//! `concurrent_writes` example for a benchmark against a single writer behind a mutex.
+//!
+//! ### Compaction
+//! Flushed segments wait in level 0 until [`LSMBuilder::compaction_trigger`] of them have piled up (by default,
+//! just one), and are then merged together with the sorted run into a fresh one. A [`CompactionFilter`]
+//! registered on the builder sees each live entry as it is rewritten, and can keep it, drop it or change its value.
+//!
+//! A [`RateLimiter`] caps the bandwidth flushes and compactions write with, so that they leave the disk to other
+//! engines and processes. Reads of the same engine still wait for a flush or compaction to finish, and a limiter only
+//! makes that take longer. When merging falls behind, writes are slowed down and eventually stopped until it catches
+//! up; see [`LSMBuilder::slowdown_writes_trigger`] and [`LSMBuilder::stop_writes_trigger`].
+//!
+//! ### Data directory
+//! [`LSMBuilder::open`] keeps the engine in a directory of its own: the segment files, a MANIFEST listing them, and a
+//! WAL holding whatever was written since the last flush, so that [`LSMEngine::open`] picks up where it left off.
+//! [`LSMEngine::checkpoint`] takes a consistent copy of it while the engine keeps running, hard-linking the segments.
+//! A [`BackupEngine`] keeps a series of such copies, sharing the segments they have in common. The manifest records
+//! a checksum of every segment, which [`verify_dir`] checks along with everything else it can from outside the
+//! engine, while [`LSMEngine::verify`] also checks a running engine's sparse index and bloom filter. When a data
+//! directory no longer opens, [`LSMEngine::repair`] salvages what it can.
+//!
+//! ### Bulk loading
+//! Large loads can skip the write path altogether: an [`SstWriter`] builds a segment file offline from sorted keys,
+//! and [`LSMEngine::ingest_external_files`] slots such files into the engine in one step.
+//!
+//! ### Replication
+//! Every record logged to the WAL gets the next sequence number. [`LSMEngine::wal_iterator`] reads back the records
+//! after a given one, as far back as [`LSMBuilder::wal_retention`] keeps old WALs. A [`WalShipper`] streams them over
+//! TCP, and a [`Follower`] applies them to a read-only engine of its own, as a warm standby. Downstream consumers,
+//! such as indexers, can instead follow each write as it is committed through [`LSMEngine::subscribe`].
+//!
+//! ### Server
+//! With the `server` feature, a `Server` shares a [`ConcurrentEngine`] with other processes over TCP, speaking a
+//! subset of the Redis protocol, and a `Client` talks to it. With the `http` feature, an `HttpServer` does the same
+//! over HTTP, with JSON bodies.
+//!
+//! ### Async
+//! With the `async` feature, an `AsyncDb` offers `async` versions of the basic calls, run on worker threads of its
+//! own so that they never block the executor.
+//!
 //! For more details with visual illustrations, check out this [blog post](https://navyazaveri.github.io/algorithms/2020/01/12/write-a-kv-store-from-scratch.html)
 //!
 
+// explicit returns are the house style
+#![allow(clippy::needless_return)]
+// and so are the older spellings in the code and tests that predate the lints
+#![allow(clippy::redundant_field_names, clippy::seek_from_current, clippy::useless_vec, clippy::bool_assert_comparison, clippy::needless_range_loop)]
+
 use crate::memtable::{Memtable};
 use crate::sst::{Segment};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::ops::Bound::{Included, Unbounded};
 use rand::Rng;
 use thiserror::Error;
 use rand::distributions::Alphanumeric;
-use crate::kv::{KVPair, KVFileWriter, KVFileReader};
+use crate::kv::{KVPair, KVFileIterator, KVFileReader};
 use crate::wal::Wal;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
+use crate::range_tombstone::RangeTombstone;
+use crate::transaction::ConflictTracker;
+use crate::lock::LockManager;
+use crate::compaction::ChangeRecorder;
+use crate::write_stall::{Condition, WriteStall};
+use crate::manifest::{Manifest, SegmentEntry, Storage, WalEntry};
+use crate::replication::WalReaders;
+use std::time::{Duration, Instant};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
 use rand::{SeedableRng};
+use std::sync::Arc;
+use std::thread;
 
 extern crate bloom;
 
@@ -91,9 +166,68 @@ extern crate lazy_static;
 
 
 mod memtable;
+mod memtable_rep;
+mod concurrent;
 mod sst;
 mod wal;
 mod kv;
+mod merge;
+mod compaction;
+mod range_tombstone;
+mod batch;
+mod transaction;
+mod lock;
+mod stats;
+mod properties;
+mod events;
+mod rate_limiter;
+mod write_stall;
+mod write_buffer;
+mod sst_writer;
+mod manifest;
+mod backup;
+mod record;
+mod checksum;
+mod verify;
+mod repair;
+mod net;
+mod replication;
+mod subscription;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "async")]
+mod async_db;
+
+pub use crate::merge::MergeOperator;
+pub use crate::compaction::{CompactionFilter, CompactionStats, Decision};
+pub use crate::batch::WriteBatch;
+pub use crate::transaction::{PessimisticTransaction, Transaction};
+pub use crate::stats::{prometheus_text, Histogram, Stats};
+pub use crate::properties::{Description, LevelDescription, SegmentDescription};
+pub use crate::events::{CompactionInfo, EventListener, FlushInfo};
+pub use crate::rate_limiter::RateLimiter;
+pub use crate::write_buffer::WriteBufferManager;
+pub use crate::memtable_rep::{BTreeRep, ConcurrentMemtableRep, HashRep, MemtableRep, SkipListRep, VectorRep};
+pub use crate::concurrent::ConcurrentEngine;
+pub use crate::sst_writer::{ExternalFileInfo, SstWriter};
+pub use crate::backup::{BackupEngine, BackupInfo};
+pub use crate::record::Record;
+pub use crate::wal::WalReader;
+pub use crate::sst::SegmentReader;
+pub use crate::verify::{verify_dir, Problem};
+pub use crate::repair::RepairReport;
+pub use crate::net::{Listener, ServerHandle};
+pub use crate::replication::{Follower, ReplicationStream, WalIterator, WalShipper};
+pub use crate::subscription::Subscription;
+#[cfg(feature = "server")]
+pub use crate::server::{Client, Server};
+#[cfg(feature = "http")]
+pub use crate::http::HttpServer;
+#[cfg(feature = "async")]
+pub use crate::async_db::AsyncDb;
+
 lazy_static! {
 
 static ref TOMBSTONE_VALUE: String = {
@@ -112,19 +246,93 @@ pub enum Error {
     SstError(#[from] sst::SstError),
     #[error(transparent)]
     KvError(#[from] kv::KvError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("merge operands were found but no merge operator is registered")]
+    MissingMergeOperator,
+    #[error("transaction conflict: {} was modified after the transaction began", key)]
+    TransactionConflict { key: String },
+    #[error("the transaction began on another engine")]
+    ForeignTransaction,
+    #[error("timed out waiting for the lock on {}", key)]
+    LockTimeout { key: String },
+    #[error("waiting for the lock on {} would deadlock", key)]
+    Deadlock { key: String },
+    #[error("the group commit this write belonged to failed: {}", message)]
+    WriteGroupFailed { message: String },
+    #[error("the worker thread making the call panicked")]
+    WorkerPanicked,
+    #[error("cannot ingest {}: {}", path, reason)]
+    IngestFailed { path: String, reason: String },
+    #[error("{} is corrupted: {}", file, reason)]
+    Corruption { file: String, reason: String },
+    #[error("the server replied with an error: {}", message)]
+    ServerError { message: String },
+    #[error("protocol error: {}", reason)]
+    ProtocolError { reason: String },
+    #[error("record {} is no longer in the WAL, which now starts at {}", sequence, oldest)]
+    SequenceUnavailable { sequence: u64, oldest: u64 },
+    #[error("expected record {} next but got {}", expected, found)]
+    ReplicationGap { expected: u64, found: u64 },
+    #[error("record {} ingested files, which followers can't apply: seed the follower from a checkpoint instead", sequence)]
+    IngestNotReplicated { sequence: u64 },
+    #[error("only an engine in a data directory can do that")]
+    NeedsDataDirectory,
+    #[error("subscribers only see writes that are synced to disk, which takes an engine built with wal_sync(true)")]
+    NeedsWalSync,
+    #[error("the value for {} starts with a marker the engine reserves for its own records", key)]
+    ReservedValue { key: String },
+    #[error("backup {} does not exist", id)]
+    BackupNotFound { id: u64 },
+    #[error("backup {} is corrupted: {}", id, reason)]
+    BackupCorrupted { id: u64, reason: String },
 }
 
 
 pub type Result<T> = std::result::Result<T, self::Error>;
 
 pub struct LSMEngine {
-    memtable: Memtable<String, String>,
+    memtable: Memtable,
+    //flushed segments waiting to be merged, oldest first; their key ranges may overlap
+    level0: Vec<Segment>,
+    pending_compaction_bytes: u64,
+    compaction_trigger: usize,
+    //the sorted run every merge produces
     segments: Vec<Segment>,
     segment_size: usize,
     sparse_memory_index: BTreeMap<String, (KeyOffset, SegmentIndex)>,
     sparse_offset: usize,
     wal: Option<Wal>,
+    wal_sync: bool,
+    //where the records of the write in progress start in the WAL, and the sequence number of the first one
+    logged_at: Option<u64>,
+    logged_sequence: Option<u64>,
+    //the sequence number of the last record logged, and of the first one in the current WAL
+    sequence: u64,
+    wal_start: u64,
+    //the data directory the segments, WAL and manifest live in, if any
+    storage: Option<Storage>,
+    wal_number: Option<u64>,
+    //older WALs kept around for replication, oldest first, with the sequence number of each one's first record
+    archived_wals: VecDeque<(u64, u64)>,
+    wal_retention: usize,
+    wal_readers: Arc<WalReaders>,
+    //files to delete once the manifest no longer lists them
+    obsolete_files: Vec<PathBuf>,
     bloom_filter: BloomFilter,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    compaction_stats: CompactionStats,
+    range_tombstones: Vec<RangeTombstone>,
+    conflicts: ConflictTracker,
+    locks: Arc<LockManager>,
+    lock_timeout: Duration,
+    stats: Stats,
+    listeners: Vec<Arc<dyn EventListener>>,
+    rate_limiter: Option<RateLimiter>,
+    write_stall: WriteStall,
 }
 
 
@@ -134,6 +342,25 @@ pub struct LSMBuilder {
     sparse_offset: usize,
     inmemory_capacity: usize,
     wal: Option<Wal>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    lock_timeout: Duration,
+    latency_histograms: bool,
+    listeners: Vec<Arc<dyn EventListener>>,
+    compaction_trigger: usize,
+    rate_limiter: Option<RateLimiter>,
+    write_stall: WriteStall,
+    write_buffer_size: Option<usize>,
+    write_buffer_manager: Option<WriteBufferManager>,
+    memtable_rep: Option<Box<dyn MemtableRep>>,
+    wal_sync: bool,
+    wal_retention: usize,
+}
+
+impl Default for LSMBuilder {
+    fn default() -> Self {
+        return Self::new();
+    }
 }
 
 impl LSMBuilder {
@@ -144,6 +371,19 @@ impl LSMBuilder {
             sparse_offset: 35,
             inmemory_capacity: 500,
             wal: None,
+            merge_operator: None,
+            compaction_filter: None,
+            lock_timeout: Duration::from_secs(1),
+            latency_histograms: false,
+            listeners: Vec::new(),
+            compaction_trigger: 1,
+            rate_limiter: None,
+            write_stall: WriteStall::default(),
+            write_buffer_size: None,
+            write_buffer_manager: None,
+            memtable_rep: None,
+            wal_sync: false,
+            wal_retention: 0,
         };
     }
 
@@ -166,18 +406,156 @@ impl LSMBuilder {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)
             .unwrap();
         self.wal = Some(Wal::new(file));
         return self;
     }
 
+    /// Whether every WAL append is flushed to disk before the write returns. Off by default, so writes acknowledged
+    /// just before a crash of the machine (rather than the process) may be lost.
+    pub fn wal_sync(mut self, sync: bool) -> Self {
+        self.wal_sync = sync;
+        return self;
+    }
+
+    /// How many WAL files a data directory keeps after it is done with them, so that
+    /// [`wal_iterator`](LSMEngine::wal_iterator) can reach further back than the current one. None by default: the
+    /// WALs that open iterators are still reading are kept regardless.
+    pub fn wal_retention(mut self, files: usize) -> Self {
+        self.wal_retention = files;
+        return self;
+    }
+
     pub fn inmemory_capacity(mut self, inmemory_capacity: usize) -> Self {
         self.inmemory_capacity = inmemory_capacity;
         return self;
     }
+
+    /// Flushes the memtable once its keys, values and bookkeeping take up `bytes` of memory, even if it holds fewer
+    /// than `inmemory_capacity` entries.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer_size = Some(bytes);
+        return self;
+    }
+
+    /// Charges the memtable to a memory budget shared with other engines; see [`WriteBufferManager`].
+    pub fn write_buffer_manager(mut self, manager: WriteBufferManager) -> Self {
+        self.write_buffer_manager = Some(manager);
+        return self;
+    }
+
+    /// Stores the memtable's entries in `rep` instead of the default [`BTreeRep`].
+    pub fn memtable_rep<R: MemtableRep + 'static>(mut self, rep: R) -> Self {
+        self.memtable_rep = Some(Box::new(rep));
+        return self;
+    }
+
+    /// Registers the operator used to combine the operands written with [`LSMEngine::merge`].
+    pub fn merge_operator<M: MergeOperator + 'static>(mut self, operator: M) -> Self {
+        self.merge_operator = Some(Arc::new(operator));
+        return self;
+    }
+
+    /// Registers a filter run over every live entry rewritten by a compaction.
+    pub fn compaction_filter<C: CompactionFilter + 'static>(mut self, filter: C) -> Self {
+        self.compaction_filter = Some(Arc::new(filter));
+        return self;
+    }
+
+    /// How long a pessimistic transaction waits for a key lock before giving up.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        return self;
+    }
+
+    /// Whether [`LSMEngine::stats`] should include read and write latency histograms.
+    pub fn latency_histograms(mut self, enabled: bool) -> Self {
+        self.latency_histograms = enabled;
+        return self;
+    }
+
+    /// Registers a listener for flush, compaction and WAL events. Listeners are invoked in registration order.
+    pub fn event_listener<L: EventListener + 'static>(mut self, listener: L) -> Self {
+        self.listeners.push(Arc::new(listener));
+        return self;
+    }
+
+    /// How many flushed segments accumulate before they are merged into the sorted run. The default of 1 merges on
+    /// every flush; a higher trigger writes less at the cost of reads searching more segments.
+    pub fn compaction_trigger(mut self, segments: usize) -> Self {
+        self.compaction_trigger = segments.max(1);
+        return self;
+    }
+
+    /// Caps the bandwidth flushes and compactions write segments with. They run while the engine is borrowed, so
+    /// reads of this engine wait the longer for them.
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        return self;
+    }
+
+    /// Delays writes to the [`delayed_write_rate`](LSMBuilder::delayed_write_rate) once this many segments wait to
+    /// be merged.
+    pub fn slowdown_writes_trigger(mut self, segments: usize) -> Self {
+        self.write_stall.slowdown_segments = Some(segments);
+        return self;
+    }
+
+    /// Stops writes until the unmerged segments are merged once this many of them pile up.
+    pub fn stop_writes_trigger(mut self, segments: usize) -> Self {
+        self.write_stall.stop_segments = Some(segments);
+        return self;
+    }
+
+    /// Delays writes once the unmerged segments hold this many bytes.
+    pub fn soft_pending_compaction_bytes_limit(mut self, bytes: u64) -> Self {
+        self.write_stall.slowdown_pending_bytes = Some(bytes);
+        return self;
+    }
+
+    /// Stops writes until the unmerged segments are merged once they hold this many bytes.
+    pub fn hard_pending_compaction_bytes_limit(mut self, bytes: u64) -> Self {
+        self.write_stall.stop_pending_bytes = Some(bytes);
+        return self;
+    }
+
+    /// The rate, in bytes of keys and values per second, that delayed writes are held to. Defaults to 16MiB/s.
+    pub fn delayed_write_rate(mut self, bytes_per_second: u64) -> Self {
+        self.write_stall.delayed_write_rate = bytes_per_second;
+        return self;
+    }
+
     pub fn build(self) -> LSMEngine {
-        return LSMEngine::new(self.inmemory_capacity, self.segment_size, self.sparse_offset, self.wal);
+        let mut lsm = LSMEngine::new(self.inmemory_capacity, self.segment_size, self.sparse_offset, self.wal);
+        let mut memtable = Memtable::new(self.inmemory_capacity);
+        if let Some(rep) = self.memtable_rep {
+            memtable = memtable.with_rep(rep);
+        }
+        lsm.memtable = memtable
+            .with_write_buffer_size(self.write_buffer_size)
+            .with_write_buffer_manager(self.write_buffer_manager);
+        lsm.merge_operator = self.merge_operator;
+        lsm.compaction_filter = self.compaction_filter;
+        lsm.lock_timeout = self.lock_timeout;
+        lsm.stats = Stats::with_histograms(self.latency_histograms);
+        lsm.listeners = self.listeners;
+        lsm.compaction_trigger = self.compaction_trigger;
+        lsm.rate_limiter = self.rate_limiter;
+        lsm.write_stall = self.write_stall;
+        lsm.wal_sync = self.wal_sync;
+        lsm.wal_retention = self.wal_retention;
+        return lsm;
+    }
+
+    /// Builds an engine keeping its segments, WAL and manifest in the data directory `dir`, creating it if needed
+    /// and otherwise picking up where the engine last left off. Any [`wal_path`](LSMBuilder::wal_path) is ignored.
+    pub fn open<P: AsRef<Path>>(mut self, dir: P) -> Result<LSMEngine> {
+        self.wal = None;
+        let mut lsm = self.build();
+        lsm.load(dir.as_ref())?;
+        return Ok(lsm);
     }
 }
 
@@ -189,16 +567,40 @@ impl LSMEngine {
 
         LSMEngine {
             memtable: Memtable::new(inmemory_capacity),
+            level0: Vec::new(),
+            pending_compaction_bytes: 0,
+            compaction_trigger: 1,
             segments: Vec::new(),
             sparse_memory_index: BTreeMap::new(),
             segment_size,
             sparse_offset,
             wal,
+            wal_sync: false,
+            logged_at: None,
+            logged_sequence: None,
+            sequence: 0,
+            wal_start: 1,
+            storage: None,
+            wal_number: None,
+            archived_wals: VecDeque::new(),
+            wal_retention: 0,
+            wal_readers: Arc::default(),
+            obsolete_files: Vec::new(),
 
             // we don't care about high false positivity rate (0.9) since we're only using the bloom filter
             // to detect keys _not_ inserted into the db (ie, false negatives)
             bloom_filter: BloomFilter::with_rate(0.9, 10000),
-
+            merge_operator: None,
+            compaction_filter: None,
+            compaction_stats: CompactionStats::default(),
+            range_tombstones: Vec::new(),
+            conflicts: ConflictTracker::default(),
+            locks: Arc::new(LockManager::default()),
+            lock_timeout: Duration::from_secs(1),
+            stats: Stats::default(),
+            listeners: Vec::new(),
+            rate_limiter: None,
+            write_stall: WriteStall::default(),
         }
     }
 
@@ -206,76 +608,853 @@ impl LSMEngine {
     pub fn recover_from(&mut self, wal_file: File) -> Result<()> {
         self.clear();
         let mut wal_file = Wal::new(wal_file);
+        self.sequence = self.replay(&mut wal_file)?;
+        self.wal_start = 1;
+        self.wal = Some(wal_file);
+        for listener in self.listeners.iter() {
+            listener.on_wal_rotated();
+        }
+        Ok(())
+    }
+
+    /// Opens the engine in the data directory `dir` with the default options; see [`LSMBuilder::open`].
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<LSMEngine> {
+        return LSMBuilder::new().open(dir);
+    }
 
-        for maybe_kv in wal_file.read_from_start()? {
-            let kv = maybe_kv?;
-            self.write(kv.key, kv.value)?;
+    /// Rebuilds the data directory `dir` from whatever can still be read, so that it opens again after its manifest
+    /// or files were damaged. Every segment is written out again without the entries that can't be read or are out
+    /// of order, likewise the WAL, and a fresh manifest lists what was kept. The WAL records kept take sequence
+    /// numbers past those issued before. The report says what was lost.
+    pub fn repair<P: AsRef<Path>>(dir: P) -> Result<RepairReport> {
+        return repair::repair(dir.as_ref());
+    }
+
+    fn load(&mut self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let manifest = Manifest::load(dir)?.unwrap_or_default();
+        let storage = Storage::new(dir, manifest.next_file);
+        storage.remove_orphans(&manifest)?;
+        let timestamp = manifest.timestamps();
+
+        let mut count = 0;
+        for (segment_index, entry) in manifest.run.iter().enumerate() {
+            let (bloom_filter, sparse_memory_index) = (&mut self.bloom_filter, &mut self.sparse_memory_index);
+            let sparse_offset = self.sparse_offset;
+            let segment = Segment::open(&storage.segment_path(entry.file), entry.file, timestamp(entry.sequence), |key_offset, key| {
+                bloom_filter.insert(&key);
+                if count % sparse_offset == 0 {
+                    sparse_memory_index.insert(key.to_owned(), (key_offset, segment_index));
+                }
+                count += 1;
+            })?;
+            check_checksum(&segment, entry)?;
+            self.segments.push(segment);
         }
-        self.wal = Some(wal_file);
+        for entry in manifest.level0.iter() {
+            let bloom_filter = &mut self.bloom_filter;
+            let path = storage.segment_path(entry.file);
+            let segment = Segment::open(&path, entry.file, timestamp(entry.sequence), |_offset, key| bloom_filter.insert(&key))?;
+            check_checksum(&segment, entry)?;
+            self.pending_compaction_bytes += segment.bytes()?;
+            self.level0.push(segment);
+        }
+        self.range_tombstones = manifest
+            .range_tombstones
+            .iter()
+            .map(|t| RangeTombstone { start: t.start.clone(), end: t.end.clone(), created_at: timestamp(t.sequence) })
+            .collect();
+
+        //the WAL is replayed before the engine has one of its own, so nothing is logged again, and nothing goes into
+        //the manifest until every record is back in the segments
+        self.storage = Some(storage.clone());
+        self.archived_wals = manifest.archived_wals.iter().map(|entry| (entry.file, entry.start)).collect();
+        let start = manifest.wal_start.max(1);
+        self.sequence = start - 1;
+        if let Some(number) = manifest.wal {
+            self.sequence += self.replay(&mut Wal::new(File::open(storage.wal_path(number))?))?;
+            self.archive_wal(number, start);
+        }
+        self.flush(None)?;
+        let (number, file) = storage.new_wal()?;
+        self.wal = Some(Wal::new(file));
+        self.wal_number = Some(number);
+        self.wal_start = self.sequence + 1;
+        self.save_manifest()?;
+        self.publish();
         Ok(())
     }
 
+    /// Applies every record of `wal`, which must not be the engine's own WAL, returning how many there were.
+    fn replay(&mut self, wal: &mut Wal) -> Result<u64> {
+        let mut count = 0;
+        for maybe_kv in wal.read_from_start()? {
+            self.apply_record(Record::decode(maybe_kv?))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn apply_record(&mut self, record: Record) -> Result<()> {
+        match record {
+            Record::Put { key, value } => self.write(key, value),
+            Record::Delete { key } => self.write(key, TOMBSTONE_VALUE.to_string()),
+            Record::Merge { key, operands } => {
+                for operand in operands {
+                    self.merge(key.clone(), operand)?;
+                }
+                Ok(())
+            }
+            Record::DeleteRange { start, end } => self.delete_range(&start, &end),
+            Record::Batch(records) => {
+                let mut batch = WriteBatch::new();
+                for record in records {
+                    match record {
+                        Record::Put { key, value } => batch.put(key, value),
+                        Record::Delete { key } => batch.delete(&key),
+                        other => unreachable!("batches only hold puts and deletes, not {:?}", other),
+                    }
+                }
+                self.write_batch(batch)
+            }
+            //the segments ingested are in the manifest already
+            Record::Ingest { .. } => Ok(()),
+        }
+    }
+
     pub fn clear(&mut self) {
+        if let Some(storage) = self.storage.as_ref() {
+            let numbers = self.level0.iter().chain(self.segments.iter()).filter_map(Segment::number);
+            self.obsolete_files.extend(numbers.map(|number| storage.segment_path(number)));
+        }
+        self.memtable.clear();
+        self.level0.clear();
+        self.pending_compaction_bytes = 0;
         self.segments.clear();
         self.sparse_memory_index.clear();
         self.bloom_filter.clear();
+        self.range_tombstones.clear();
+        //the WAL's records are gone along with the memtable, so a fresh one takes over
+        let result = self.rotate_wal(None);
+        let _ = self.report_background_error(result);
     }
 
 
     fn flush_memtable(&mut self) -> Result<Segment> {
-        let mut new_segment = Segment::temp();
+        let started = Instant::now();
+        let entries = self.memtable.len();
+        for listener in self.listeners.iter() {
+            listener.on_flush_begin(entries);
+        }
+        let mut new_segment = manifest::new_segment(self.storage.as_ref())?;
         for (key, value) in self.memtable.drain() {
-            new_segment.write(KVPair { key, value })?;
+            let offset = new_segment.write(KVPair { key, value })?;
+            if let Some(limiter) = self.rate_limiter.as_ref() {
+                limiter.request(new_segment.tell()? - offset);
+            }
+        }
+        if self.storage.is_some() {
+            new_segment.sync()?;
+        }
+        if !self.listeners.is_empty() {
+            let info = FlushInfo { entries, bytes: new_segment.bytes()?, duration: started.elapsed() };
+            for listener in self.listeners.iter() {
+                listener.on_flush_completed(&info);
+            }
         }
         return Ok(new_segment);
     }
 
 
     fn merge_segments(&mut self) -> Result<()> {
+        let started = Instant::now();
+        let input = if self.listeners.is_empty() {
+            vec![]
+        } else {
+            let mut input = describe_segments(&self.segments)?;
+            input.extend(describe_segments(&self.level0)?);
+            input
+        };
+        for listener in self.listeners.iter() {
+            listener.on_compaction_begin(&input);
+        }
         self.sparse_memory_index.clear();
         let mut count = 0;
-        self.segments = sst::merge(std::mem::take(&mut self.segments), self.segment_size,
-                                   |segment_index, key_offset, key| {
-                                       if count % self.sparse_offset == 0 {
-                                           self.sparse_memory_index.insert(key, (key_offset, segment_index));
-                                       }
-                                       count += 1;
-                                   })?;
+        let merge_operator = self.merge_operator.clone();
+        let compaction_filter = self.compaction_filter.clone();
+        //what the filter removes or changes is modified as far as open transactions are concerned
+        let recorder = compaction_filter.clone().filter(|_| self.conflicts.tracking()).map(ChangeRecorder::new);
+        let filter: Option<&dyn CompactionFilter> = match recorder.as_ref() {
+            Some(recorder) => Some(recorder),
+            None => compaction_filter.as_deref(),
+        };
+        //every segment takes part in the merge, so range tombstones are fully applied by it
+        let range_tombstones = std::mem::take(&mut self.range_tombstones);
+        let rate_limiter = self.rate_limiter.clone();
+        let mut inputs = std::mem::take(&mut self.segments);
+        inputs.append(&mut self.level0);
+        self.pending_compaction_bytes = 0;
+        let storage = self.storage.clone();
+        if let Some(storage) = storage.as_ref() {
+            self.obsolete_files.extend(inputs.iter().filter_map(Segment::number).map(|number| storage.segment_path(number)));
+        }
+        let (segments, stats) = sst::merge(inputs, self.segment_size, &|| manifest::new_segment(storage.as_ref()),
+                                           merge_operator.as_deref(), filter, &range_tombstones,
+                                           rate_limiter.as_ref(),
+                                           |segment_index, key_offset, key| {
+                                               if count % self.sparse_offset == 0 {
+                                                   self.sparse_memory_index.insert(key, (key_offset, segment_index));
+                                               }
+                                               count += 1;
+                                           })?;
+        if self.storage.is_some() {
+            for segment in segments.iter() {
+                segment.sync()?;
+            }
+        }
+        self.segments = segments;
+        for key in recorder.map_or(vec![], ChangeRecorder::into_changed) {
+            self.conflicts.record_key(&key);
+        }
+        self.save_manifest()?;
+        self.stats.compactions += 1;
+        self.stats.compaction_bytes_written += stats.bytes_written;
+        self.stats.compaction_time += started.elapsed();
+        if !self.listeners.is_empty() {
+            let output = describe_segments(&self.segments)?;
+            let info = CompactionInfo { input, output, stats: stats.clone(), duration: started.elapsed() };
+            for listener in self.listeners.iter() {
+                listener.on_compaction_completed(&info);
+            }
+        }
+        self.compaction_stats = stats;
         Ok(())
     }
 
+    /// A snapshot of the engine's counters; see [`prometheus_text`] to export it.
+    pub fn stats(&self) -> Stats {
+        return self.stats.clone();
+    }
+
+    /// Reports the shape of the engine: the memtable fill, the sparse index size, and every segment's size,
+    /// entry and tombstone counts and key range.
+    pub fn describe(&self) -> Result<Description> {
+        let mut levels = vec![];
+        for (level, segments) in [&self.level0, &self.segments].iter().enumerate() {
+            if !segments.is_empty() {
+                levels.push(LevelDescription { level, segments: describe_segments(segments)? });
+            }
+        }
+        return Ok(Description {
+            memtable_entries: self.memtable.len(),
+            memtable_capacity: self.memtable.capacity(),
+            memtable_bytes: self.memtable.bytes(),
+            write_buffer_size: self.memtable.write_buffer_size(),
+            sparse_index_entries: self.sparse_memory_index.len(),
+            range_tombstones: self.range_tombstones.len(),
+            levels,
+        });
+    }
+
+    /// Looks up a single property of the engine by name, returning `None` for unknown names. Supported properties:
+    /// `lsm.num-levels`, `lsm.num-segments`, `lsm.total-segment-bytes`, `lsm.num-entries-segments`,
+    /// `lsm.num-tombstones`, `lsm.num-range-tombstones`, `lsm.num-entries-memtable`, `lsm.memtable-capacity`,
+    /// `lsm.memtable-bytes`, `lsm.write-buffer-size`, `lsm.sparse-index-entries`, `lsm.num-unmerged-segments`, `lsm.pending-compaction-bytes` and `lsm.levels`,
+    /// a table with one line per segment.
+    pub fn get_property(&self, name: &str) -> Result<Option<String>> {
+        return Ok(self.describe()?.property(name));
+    }
+
+    /// Flushes the memtable and merges every segment into a fresh sorted run, regardless of the
+    /// [`compaction_trigger`](LSMBuilder::compaction_trigger).
+    pub fn compact(&mut self) -> Result<()> {
+        let result = self.flush(None).and_then(|_| self.merge_segments());
+        return self.report_background_error(result);
+    }
+
+    /// Loads segment files built with an [`SstWriter`] straight into the engine. Their keys must be sorted and the
+    /// files may not overlap one another. Every file is checked before anything changes, so either all of them are
+    /// ingested or none are. Their entries take precedence over everything already in the engine.
+    ///
+    /// A file overlapping none of the engine's segments joins the sorted run as it is; any other goes into level 0 as
+    /// its newest segment. The memtable is flushed first if it holds any of the files' keys, and always in a data
+    /// directory, where the files are recorded in the manifest and the WAL only logs a [`Record::Ingest`] saying so,
+    /// which [`Follower`]s can't apply.
+    pub fn ingest_external_files<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<()> {
+        let mut files = vec![];
+        for path in paths {
+            let segment = manifest::new_segment(self.storage.as_ref())?;
+            //a key that doesn't end up ingested only costs the bloom filter a false positive
+            let bloom_filter = &mut self.bloom_filter;
+            let (segment, index) = sst_writer::load(path.as_ref(), segment, self.sparse_offset, |key| bloom_filter.insert(&key))?;
+            if segment.size() > 0 {
+                files.push((path.as_ref().display().to_string(), segment, index));
+            }
+        }
+        files.sort_by(|a, b| a.1.first_key().cmp(&b.1.first_key()));
+        for pair in files.windows(2) {
+            if pair[1].1.first_key() <= pair[0].1.last_key() {
+                let reason = format!("its keys overlap those of {}", pair[0].0);
+                return Err(Error::IngestFailed { path: pair[1].0.clone(), reason });
+            }
+        }
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let overlaps_memtable = files.iter().any(|(_path, segment, _index)| {
+            let (first, last) = (segment.first_key().unwrap(), segment.last_key().unwrap());
+            self.memtable.range(first, last).next().is_some() || self.memtable.contains(last)
+        });
+        if overlaps_memtable || self.storage.is_some() {
+            let result = self.flush(None);
+            self.report_background_error(result)?;
+        }
+
+        if self.storage.is_some() {
+            for (_path, segment, _index) in files.iter() {
+                segment.sync()?;
+            }
+        } else {
+            //without a data directory segments don't outlive the engine, so the ingested entries are logged to be
+            //recovered like any other write
+            let mut ingested = WriteBatch::new();
+            for (_path, segment, _index) in files.iter_mut() {
+                ingested.entries.extend(segment.read_from_start()?);
+            }
+            self.log(&[KVPair { key: String::new(), value: ingested.encode() }])?;
+        }
+        for (_path, segment, _index) in files.iter() {
+            //the smallest key after the last one, so that the range covers it
+            let end = format!("{}\u{0}", segment.last_key().unwrap());
+            self.conflicts.record_range(segment.first_key().unwrap(), &end);
+        }
+
+        for (_path, mut segment, index) in files {
+            let first = segment.first_key().unwrap().to_owned();
+            let last = segment.last_key().unwrap().to_owned();
+            let overlaps = |other: &Segment| {
+                other.first_key().is_some_and(|key| key <= last.as_str()) && other.last_key().is_some_and(|key| first.as_str() <= key)
+            };
+            segment.renew_timestamp();
+            if self.level0.iter().any(overlaps) || self.segments.iter().any(overlaps) {
+                self.pending_compaction_bytes += segment.bytes()?;
+                self.level0.push(segment);
+                continue;
+            }
+            let position = self.segments.partition_point(|other| other.last_key().is_some_and(|key| key < first.as_str()));
+            for (_offset, segment_index) in self.sparse_memory_index.values_mut() {
+                if *segment_index >= position {
+                    *segment_index += 1;
+                }
+            }
+            for (key, offset) in index {
+                self.sparse_memory_index.insert(key, (offset, position));
+            }
+            self.segments.insert(position, segment);
+        }
+        self.save_manifest()?;
+        if self.storage.is_some() {
+            let paths = paths.iter().map(|path| path.as_ref().display().to_string()).collect::<Vec<_>>();
+            self.log(&[KVPair { key: String::new(), value: sst_writer::encode_ingest(&paths) }])?;
+        }
+        self.publish();
+        if self.level0.len() >= self.compaction_trigger {
+            let result = self.merge_segments();
+            self.report_background_error(result)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the engine into the new directory `dir`, which [`LSMEngine::open`] can start
+    /// from. The memtable is flushed first. The segment files are then hard-linked into `dir` if they live in a data
+    /// directory on the same file system, and copied otherwise, along with a manifest and the WAL written since the
+    /// flush. Segments from a data directory keep their file numbers.
+    pub fn checkpoint<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let result = self.flush(None);
+        self.report_background_error(result)?;
+        fs::create_dir(dir)?;
+
+        let mut numbers = vec![];
+        for (position, segment) in self.level0.iter_mut().chain(self.segments.iter_mut()).enumerate() {
+            //backups tell the segments they already hold apart by their numbers
+            let number = segment.number().unwrap_or(position as u64);
+            let target = dir.join(manifest::segment_file_name(number));
+            let source = self.storage.as_ref().zip(segment.number()).map(|(storage, source)| storage.segment_path(source));
+            if source.map_or(true, |source| fs::hard_link(source, &target).is_err()) {
+                segment.copy_to(&target)?;
+            }
+            numbers.push(number);
+        }
+        let mut manifest = self.capture_manifest(&numbers);
+        manifest.next_file = numbers.iter().max().map_or(0, |number| number + 1);
+        if let (Some(storage), Some(number)) = (self.storage.as_ref(), self.wal_number) {
+            fs::copy(storage.wal_path(number), dir.join(manifest::wal_file_name(manifest.next_file)))?;
+            manifest.wal = Some(manifest.next_file);
+            manifest.wal_start = self.wal_start;
+            manifest.next_file += 1;
+        }
+        manifest.save(dir)?;
+        Ok(())
+    }
+
+    /// What the most recent compaction did.
+    pub fn last_compaction_stats(&self) -> &CompactionStats {
+        return &self.compaction_stats;
+    }
+
+    /// Sets `key` to `value`. A value starting with one of the markers the WAL encodes merges, batches, range deletes
+    /// and ingests with is refused, since it would be replayed as one.
     pub fn write(&mut self, key: String, value: String) -> Result<()> {
+        record::check_value(&key, &value)?;
+        let started = Instant::now();
+        self.throttle(key.len() + value.len())?;
         self.write_to_wal(&key, &value)?;
+        self.apply(key, value)?;
+        self.publish();
+        self.stats.observe_write(started);
+        Ok(())
+    }
+
+    /// Applies every write in `batch`, logging them as a single WAL record.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        batch.check_values()?;
+        let started = Instant::now();
+        self.throttle(batch.entries.iter().map(|kv| kv.key.len() + kv.value.len()).sum())?;
+        self.log(&[KVPair { key: String::new(), value: batch.encode() }])?;
+        for kv in batch.entries {
+            self.apply(kv.key, kv.value)?;
+        }
+        self.publish();
+        self.stats.observe_write(started);
+        Ok(())
+    }
+
+    /// Starts an optimistic transaction; see [`Transaction`].
+    pub fn begin_transaction(&mut self) -> Transaction {
+        return Transaction::new(self.conflicts.snapshot());
+    }
+
+    /// Starts a pessimistic transaction; see [`PessimisticTransaction`].
+    pub fn begin_pessimistic_transaction(&self) -> PessimisticTransaction {
+        return PessimisticTransaction::new(self.locks.clone(), self.lock_timeout);
+    }
+
+    /// Lets WAL iterators read as far as the last record logged, now that it has been applied.
+    fn publish(&self) {
+        self.wal_readers.commit(self.sequence);
+    }
+
+    /// Applies an already logged write to the in-memory state.
+    fn apply(&mut self, key: String, value: String) -> Result<()> {
+        self.stats.writes += 1;
+        self.conflicts.record_key(&key);
         self.bloom_filter.insert(&key);
-        if self.memtable.at_capacity() && !self.memtable.contains(&key) {
-            let new_segment = self.flush_memtable()?;
-            self.segments.push(new_segment);
-            self.memtable.insert(key, value);
-            self.merge_segments()?;
+        self.insert(key, value)
+    }
+
+    /// Records `operand` against `key` without reading the current value. The registered [`MergeOperator`]
+    /// combines it with the existing value on the next `read`, or during compaction.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        let started = Instant::now();
+        let operator = self.merge_operator.clone().ok_or(Error::MissingMergeOperator)?;
+        self.throttle(key.len() + operand.len())?;
+        self.write_to_wal(&key, &merge::encode_operands(std::slice::from_ref(&operand)))?;
+        self.stats.writes += 1;
+        self.conflicts.record_key(&key);
+        self.bloom_filter.insert(&key);
+
+        let value = match self.memtable.get(&key) {
+            Some(existing) => match merge::decode_operands(&existing) {
+                Some(mut operands) => {
+                    operands.push(operand);
+                    merge::encode_operands(&operands)
+                }
+                //the newest complete value is already at hand, so there is nothing to gain by deferring
+                None => {
+                    let existing = Some(existing.as_str()).filter(|value| *value != TOMBSTONE_VALUE.as_str());
+                    operator.full_merge(&key, existing, &[operand])
+                }
+            },
+            None => merge::encode_operands(&[operand]),
+        };
+        self.insert(key, value)?;
+        self.publish();
+        self.stats.observe_write(started);
+        Ok(())
+    }
+
+    fn insert(&mut self, key: String, value: String) -> Result<()> {
+        if self.memtable.is_full(&key) {
+            let result = self.flush_and_merge(key, value);
+            self.report_background_error(result)?;
         } else {
             self.memtable.insert(key, value);
         }
         Ok(())
     }
 
-    pub fn write_to_wal(&mut self, key: &String, value: &String) -> Result<()> {
-        if self.wal.is_some() {
-            self.wal.as_mut().unwrap().persist(KVPair { key: key.clone(), value: value.clone() })?;
+    fn flush_and_merge(&mut self, key: String, value: String) -> Result<()> {
+        //the write in progress was logged, but it isn't all in the memtable yet
+        self.flush(self.logged_at)?;
+        self.memtable.insert(key, value);
+        if self.level0.len() >= self.compaction_trigger {
+            self.merge_segments()?;
+        }
+        Ok(())
+    }
+
+    /// Moves the memtable, if it holds anything, into a new unmerged segment. In a data directory, the WAL is then
+    /// rotated, keeping only the records from `unapplied` on: those of a write whose entries aren't all in the
+    /// memtable yet.
+    fn flush(&mut self, unapplied: Option<u64>) -> Result<()> {
+        if self.memtable.len() > 0 {
+            let new_segment = self.flush_memtable()?;
+            self.stats.memtable_flushes += 1;
+            self.pending_compaction_bytes += new_segment.bytes()?;
+            self.level0.push(new_segment);
+        }
+        return self.rotate_wal(unapplied);
+    }
+
+    /// Appends `records` to the WAL, if there is one, as a single write.
+    fn log(&mut self, records: &[KVPair]) -> Result<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            self.logged_at = Some(wal.tell()?);
+            self.logged_sequence = Some(self.sequence + 1);
+            wal.append(records, self.wal_sync)?;
+            self.sequence += records.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Switches a data directory over to a new WAL starting with the old one's records from `unapplied` on, and
+    /// records it in the manifest. Everything before them is in the segments by now.
+    fn rotate_wal(&mut self, unapplied: Option<u64>) -> Result<()> {
+        let (storage, wal) = match (self.storage.as_ref(), self.wal.as_mut()) {
+            (Some(storage), Some(wal)) => (storage, wal),
+            _ => return Ok(()),
+        };
+        let (number, mut file) = storage.new_wal()?;
+        if let Some(offset) = unapplied {
+            wal.seek(offset)?;
+            std::io::copy(&mut wal.file, &mut file)?;
+        }
+        file.sync_data()?;
+        if let Some(old) = self.wal_number {
+            self.archive_wal(old, self.wal_start);
+        }
+        //the records copied over keep their sequence numbers
+        self.wal_start = match unapplied {
+            Some(_) => self.logged_sequence.unwrap_or(self.sequence + 1),
+            None => self.sequence + 1,
+        };
+        self.wal = Some(Wal::new(file));
+        self.wal_number = Some(number);
+        self.logged_at = None;
+        self.logged_sequence = None;
+        self.save_manifest()?;
+        for listener in self.listeners.iter() {
+            listener.on_wal_rotated();
+        }
+        Ok(())
+    }
+
+    /// Keeps the WAL numbered `number`, whose first record has sequence number `start`, for as long as
+    /// [`wal_retention`](LSMBuilder::wal_retention) allows, and marks what falls out of it obsolete, unless an
+    /// iterator is still reading it.
+    fn archive_wal(&mut self, number: u64, start: u64) {
+        let storage = self.storage.as_ref().expect("only data directories rotate their WAL");
+        self.archived_wals.push_back((number, start));
+        let oldest_read = self.wal_readers.oldest().unwrap_or(u64::MAX);
+        while self.archived_wals.len() > self.wal_retention && self.archived_wals[0].0 < oldest_read {
+            let (number, _start) = self.archived_wals.pop_front().unwrap();
+            self.obsolete_files.push(storage.wal_path(number));
+        }
+    }
+
+    /// Describes the segments and range tombstones in a manifest, given the file numbers of level 0's segments
+    /// followed by the sorted run's.
+    fn capture_manifest(&self, numbers: &[u64]) -> Manifest {
+        let mut level0 = numbers.iter().copied().zip(self.level0.iter().chain(self.segments.iter())).collect::<Vec<_>>();
+        let run = level0.split_off(self.level0.len());
+        return Manifest::capture(&level0, &run, &self.range_tombstones);
+    }
+
+    /// Records the engine's current state in the data directory, if there is one, then deletes the files it no
+    /// longer needs. While the engine recovers, before it has a WAL, there is no state worth recording yet.
+    fn save_manifest(&mut self) -> Result<()> {
+        let storage = match (self.storage.as_ref(), self.wal.as_ref()) {
+            (Some(storage), Some(_wal)) => storage,
+            _ => return Ok(()),
+        };
+        let numbers = self.level0.iter().chain(self.segments.iter()).map(|segment| segment.number().expect("segments in a data directory are numbered"));
+        let mut manifest = self.capture_manifest(&numbers.collect::<Vec<_>>());
+        manifest.next_file = storage.next_file();
+        manifest.wal = self.wal_number;
+        manifest.wal_start = self.wal_start;
+        manifest.archived_wals = self.archived_wals.iter().map(|(file, start)| WalEntry { file: *file, start: *start }).collect();
+        manifest.save(storage.dir())?;
+        for path in self.obsolete_files.drain(..) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn report_background_error<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(error) = result.as_ref() {
+            for listener in self.listeners.iter() {
+                listener.on_background_error(error);
+            }
+        }
+        return result;
+    }
+
+    /// Holds back a write of `bytes` while merging falls behind flushing: past the slowdown triggers the write is
+    /// delayed, and past the stop triggers it waits for the unmerged segments to be merged.
+    fn throttle(&mut self, bytes: usize) -> Result<()> {
+        let delay = self.write_delay(bytes)?;
+        if !delay.is_zero() {
+            thread::sleep(delay);
         }
         Ok(())
     }
 
+    /// Like [`throttle`](LSMEngine::throttle), except that a delayed write is handed back its delay to sleep off
+    /// rather than sleeping in the engine.
+    fn write_delay(&mut self, bytes: usize) -> Result<Duration> {
+        match self.write_stall.condition(self.level0.len(), self.pending_compaction_bytes) {
+            Condition::Normal => {}
+            Condition::Delayed => {
+                let delay = self.write_stall.delay(bytes);
+                self.stats.write_slowdowns += 1;
+                self.stats.write_stall_time += delay;
+                return Ok(delay);
+            }
+            Condition::Stopped => {
+                let started = Instant::now();
+                let result = self.merge_segments();
+                self.report_background_error(result)?;
+                self.stats.write_stops += 1;
+                self.stats.write_stall_time += started.elapsed();
+            }
+        }
+        Ok(Duration::default())
+    }
+
+    pub fn write_to_wal(&mut self, key: &str, value: &str) -> Result<()> {
+        return self.log(&[KVPair { key: key.to_owned(), value: value.to_owned() }]);
+    }
+
+    /// Logs the batches of a group of concurrent writers with a single WAL append. If the memtable allows it, the
+    /// batches are handed back for their writers to insert concurrently, followed by [`finish_group`]; otherwise
+    /// they are applied one after the other right away. Also returns how long the group is to be delayed for, which the
+    /// caller sleeps off once it has let go of the engine.
+    ///
+    /// [`finish_group`]: LSMEngine::finish_group
+    pub(crate) fn commit_group(&mut self, group: Vec<WriteBatch>) -> Result<(Option<Vec<WriteBatch>>, Duration)> {
+        let bytes = group.iter().flat_map(|batch| batch.entries.iter()).map(|kv| kv.key.len() + kv.value.len()).sum();
+        let delay = self.write_delay(bytes)?;
+        let records = group
+            .iter()
+            .filter(|batch| !batch.is_empty())
+            .map(|batch| match batch.entries.as_slice() {
+                [kv] => kv.clone(),
+                _ => KVPair { key: String::new(), value: batch.encode() },
+            })
+            .collect::<Vec<_>>();
+        self.log(&records)?;
+        self.stats.write_groups += 1;
+
+        //concurrent inserts of the same key would race, so those groups go in one write at a time
+        let mut keys = HashSet::new();
+        let distinct = group.iter().flat_map(|batch| batch.entries.iter()).all(|kv| keys.insert(kv.key.as_str()));
+        if distinct && self.memtable.allows_concurrent_insert() && self.memtable.has_room_for(keys.len()) {
+            return Ok((Some(group), delay));
+        }
+        for kv in group.into_iter().flat_map(|batch| batch.entries) {
+            self.apply(kv.key, kv.value)?;
+        }
+        self.publish();
+        return Ok((None, delay));
+    }
+
+    /// Inserts a batch already logged by [`commit_group`](LSMEngine::commit_group), alongside the other writers of
+    /// its group.
+    pub(crate) fn insert_concurrently(&self, batch: WriteBatch) {
+        for kv in batch.entries {
+            self.memtable.insert_concurrently(kv.key, kv.value);
+        }
+    }
+
+    /// Does the bookkeeping `apply` would have done for the `keys` inserted concurrently.
+    pub(crate) fn finish_group(&mut self, keys: &[String]) {
+        for key in keys {
+            self.stats.writes += 1;
+            self.conflicts.record_key(key);
+            self.bloom_filter.insert(key);
+        }
+        self.publish();
+    }
+
     ///Unfortunately this is marked as mutable since relies on rust's seek api, which is also
     /// mutable. In the future, this might change to immutable if the seek api changes
     /// or if the issue becomes significant enough to warrant  using `Rc<RefCell<>>`
     pub fn read(&mut self, key: &str) -> Result<Option<String>> {
-        if let Some(value) = self.memtable.get(key) {
-            if value == &*TOMBSTONE_VALUE {
-                return Ok(None);
+        let started = Instant::now();
+        self.stats.reads += 1;
+        let (operands, stored) = match self.memtable.get(key) {
+            Some(value) => match merge::decode_operands(&value) {
+                Some(operands) => self.search_segments(key, operands)?,
+                None => (vec![], Some(value)),
+            },
+            None => self.search_segments(key, vec![])?,
+        };
+        let value = self.resolve(key, operands, stored)?;
+        self.stats.observe_read(started);
+        return Ok(value);
+    }
+
+    /// Looks up many keys at once, returning their values in the order of `keys`. The keys are sorted and grouped by
+    /// the sparse index block they fall in, so each block is scanned at most once.
+    pub fn multi_get(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let started = Instant::now();
+        self.stats.reads += keys.len() as u64;
+        let mut sorted = keys.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+
+        let mut operands: HashMap<&str, Vec<String>> = HashMap::new();
+        let mut pending = vec![];
+        for key in sorted {
+            match self.memtable.get(key).map(|value| merge::decode_operands(&value)) {
+                Some(Some(memtable_operands)) => {
+                    operands.insert(key, memtable_operands);
+                }
+                Some(None) => continue,
+                None => {}
             }
-            return Ok(Some(value.to_owned()));
+            if !self.bloom_filter.contains(&key) {
+                self.stats.bloom_filter_negatives += 1;
+                continue;
+            }
+            pending.push(key);
         }
 
+        //unmerged segments overlap, so each one is scanned for every key still missing a complete value
+        let mut stored = HashMap::new();
+        for segment in self.level0.iter_mut().rev() {
+            if pending.is_empty() {
+                break;
+            }
+            let written_at = segment.timestamp();
+            self.stats.segments_probed += 1;
+            let (found, _) = segment.search_many_from(&pending, 0)?;
+            for kv in found {
+                let key = pending.iter().copied().find(|key| *key == kv.key).unwrap();
+                if !self.range_tombstones.iter().any(|t| t.covers(key, written_at)) {
+                    match merge::decode_operands(&kv.value) {
+                        Some(mut older) => {
+                            let newer = operands.entry(key).or_default();
+                            older.append(newer);
+                            *newer = older;
+                            continue;
+                        }
+                        None => {
+                            stored.insert(kv.key, kv.value);
+                        }
+                    }
+                }
+                pending.retain(|pending_key| *pending_key != key);
+            }
+        }
+
+        let mut blocks: BTreeMap<(SegmentIndex, KeyOffset), Vec<&str>> = BTreeMap::new();
+        for key in pending {
+            let mut before = self.sparse_memory_index.range((Unbounded, Included(key.to_owned())));
+            if let Some((_closest_key, (key_offset, segment_index))) = before.next_back() {
+                blocks.entry((*segment_index, *key_offset)).or_default().push(key);
+            }
+        }
+
+        for ((segment_index, key_offset), mut pending) in blocks {
+            let mut offset = key_offset;
+            for segment in self.segments.iter_mut().skip(segment_index) {
+                if pending.is_empty() {
+                    break;
+                }
+                let written_at = segment.timestamp();
+                self.stats.segments_probed += 1;
+                let (found, remaining) = segment.search_many_from(&pending, offset)?;
+                for kv in found {
+                    if !self.range_tombstones.iter().any(|t| t.covers(&kv.key, written_at)) {
+                        stored.insert(kv.key, kv.value);
+                    }
+                }
+                pending = remaining;
+                offset = 0;
+            }
+        }
+
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = match self.memtable.get(key).filter(|value| merge::decode_operands(value).is_none()) {
+                Some(value) => self.resolve(key, vec![], Some(value))?,
+                None => {
+                    let operands = operands.get(*key).cloned().unwrap_or_default();
+                    self.resolve(key, operands, stored.get(*key).cloned())?
+                }
+            };
+            values.push(value);
+        }
+        self.stats.observe_read(started);
+        Ok(values)
+    }
+
+    /// Combines what was found for `key` into the value a reader sees: pending merge operands are applied on top
+    /// of the stored value, and tombstones read as absent.
+    fn resolve(&self, key: &str, operands: Vec<String>, stored: Option<String>) -> Result<Option<String>> {
+        //if it's marked with a tombstone value, it's a "deleted" key
+        let existing = stored.filter(|value| value != &*TOMBSTONE_VALUE);
+        if operands.is_empty() {
+            return Ok(existing);
+        }
+        let operator = self.merge_operator.as_ref().ok_or(Error::MissingMergeOperator)?;
+        return Ok(Some(operator.full_merge(key, existing.as_deref(), &operands)));
+    }
+
+    /// Looks `key` up in the unmerged segments, newest first, and then in the sorted run. Merge operands found on
+    /// the way are put in front of the newer `operands`, until a complete value (or a tombstone) ends the search.
+    fn search_segments(&mut self, key: &str, mut operands: Vec<String>) -> Result<(Vec<String>, Option<String>)> {
+        for segment in self.level0.iter_mut().rev() {
+            if !segment.in_key_range(key) {
+                continue;
+            }
+            self.stats.segments_probed += 1;
+            if let Some(value) = segment.search_from_start(key)? {
+                let written_at = segment.timestamp();
+                if self.range_tombstones.iter().any(|t| t.covers(key, written_at)) {
+                    return Ok((operands, None));
+                }
+                match merge::decode_operands(&value) {
+                    Some(mut older) => {
+                        older.append(&mut operands);
+                        operands = older;
+                    }
+                    None => return Ok((operands, Some(value))),
+                }
+            }
+        }
+        let stored = self.search_run(key)?;
+        return Ok((operands, stored));
+    }
+
+    /// Looks `key` up in the sorted run, returning whatever was stored for it (tombstones included).
+    fn search_run(&mut self, key: &str) -> Result<Option<String>> {
 
         //get the biggest element less than or equal to the key
         let mut before = self.sparse_memory_index.range((Unbounded, Included(key.to_owned())));
@@ -289,27 +1468,150 @@ impl LSMEngine {
 
         for index in *segment_index..self.segments.len() {
             let segment = &mut self.segments[index];
+            self.stats.segments_probed += 1;
             let maybe_value = if index == *segment_index { segment.search_from(key, *key_offset)? } else { segment.search_from_start(key)? };
             if maybe_value.is_some() {
-                if maybe_value.as_ref().map(|x| x != &*TOMBSTONE_VALUE).unwrap() { return Ok(maybe_value); };
-
-                //if it's marked with a tombstone value, it's a "deleted" key
-                return Ok(None);
+                let written_at = segment.timestamp();
+                if self.range_tombstones.iter().any(|t| t.covers(key, written_at)) {
+                    return Ok(None);
+                }
+                return Ok(maybe_value);
             }
         }
 
         Ok(None)
     }
     pub fn delete(&mut self, key: &str) -> Result<()> {
-        if self.wal.is_some() {
-            self.wal.as_mut().unwrap().persist(KVPair { key: key.to_owned(), value: TOMBSTONE_VALUE.to_string() })?;
-        }
         self.write(key.to_owned(), TOMBSTONE_VALUE.to_string())?;
         Ok(())
     }
 
+    /// Deletes every key in `start..end` with a single range tombstone.
+    pub fn delete_range(&mut self, start: &str, end: &str) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        self.throttle(start.len() + end.len())?;
+        self.write_to_wal(start, &range_tombstone::encode_end(end))?;
+        self.stats.writes += 1;
+        self.conflicts.record_range(start, end);
+        self.memtable.remove_range(start, end);
+        self.range_tombstones.push(RangeTombstone::new(start, end));
+        self.publish();
+        Ok(())
+    }
+
+    /// Returns every live key-value pair with a key in `start..end`, in key order.
+    pub fn scan(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let mut found = BTreeMap::new();
+
+        //the sorted run's segments hold disjoint, ascending key ranges, so the scan only ever moves forward
+        let mut before = self.sparse_memory_index.range((Unbounded, Included(start.to_owned())));
+        let (first_offset, first_segment) = before.next_back().map_or((0, 0), |(_key, position)| *position);
+        for index in first_segment..self.segments.len() {
+            let segment = &mut self.segments[index];
+            let offset = if index == first_segment { first_offset } else { 0 };
+            let written_at = segment.timestamp();
+            for kv in segment.range_from(start, end, offset)? {
+                if !self.range_tombstones.iter().any(|t| t.covers(&kv.key, written_at)) {
+                    found.insert(kv.key, kv.value);
+                }
+            }
+        }
+
+        //newer layers go on top of older ones: the unmerged segments from oldest to newest, then the memtable
+        let mut layers = vec![];
+        let range_tombstones = &self.range_tombstones;
+        for segment in self.level0.iter_mut() {
+            let written_at = segment.timestamp();
+            let mut layer = segment.range_from(start, end, 0)?;
+            layer.retain(|kv| !range_tombstones.iter().any(|t| t.covers(&kv.key, written_at)));
+            layers.push(layer);
+        }
+        let memtable = self.memtable.range(start, end).map(|(key, value)| KVPair { key, value });
+        layers.push(memtable.collect());
+
+        for kv in layers.into_iter().flatten() {
+            let value = match merge::decode_operands(&kv.value) {
+                Some(operands) => {
+                    let operator = self.merge_operator.as_ref().ok_or(Error::MissingMergeOperator)?;
+                    let existing = found.get(&kv.key).filter(|value| *value != &*TOMBSTONE_VALUE);
+                    operator.full_merge(&kv.key, existing.map(String::as_str), &operands)
+                }
+                None => kv.value,
+            };
+            found.insert(kv.key, value);
+        }
+
+        return Ok(found.into_iter().filter(|(_key, value)| value != &*TOMBSTONE_VALUE).collect());
+    }
+
+    /// Sets `key` to `new` (or deletes it, when `None`) only if its current value is `expected`, where `None`
+    /// means the key must be absent. Returns whether the condition held.
+    pub fn compare_and_swap(&mut self, key: &str, expected: Option<&str>, new: Option<String>) -> Result<bool> {
+        if self.read(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.write(key.to_owned(), value)?,
+            None if expected.is_some() => self.delete(key)?,
+            //already absent, nothing to delete
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Writes `value` only if `key` is absent. Returns whether the write happened.
+    pub fn put_if_absent(&mut self, key: &str, value: String) -> Result<bool> {
+        return self.compare_and_swap(key, None, Some(value));
+    }
+
+    /// Deletes `key` only if its current value is `expected`. Returns whether the delete happened.
+    pub fn delete_if_equals(&mut self, key: &str, expected: &str) -> Result<bool> {
+        return self.compare_and_swap(key, Some(expected), None);
+    }
+
+    /// Checks the engine's segments against their checksums, the sparse index and the bloom filter, and against the
+    /// manifest, in a data directory. Every problem found is reported, and an empty list means all is well; see
+    /// [`verify_dir`] for checking a data directory without opening it.
+    pub fn verify(&mut self) -> Result<Vec<Problem>> {
+        return verify::engine(self);
+    }
+
+    /// The sequence number of the last record logged to the WAL. Every record logged, be it a single write or a
+    /// whole batch, takes the next one, starting from 1, and a data directory carries on from there when reopened.
+    pub fn latest_sequence(&self) -> u64 {
+        return self.sequence;
+    }
+
+    /// Reads the records logged to a data directory's WAL after sequence number `since`, in order, including
+    /// those logged once the iterator exists. Records in WALs the engine is done with can only be read back as
+    /// far as [`LSMBuilder::wal_retention`] keeps them, and asking for older ones is an error.
+    pub fn wal_iterator(&self, since: u64) -> Result<WalIterator> {
+        let storage = self.storage.as_ref().ok_or(Error::NeedsDataDirectory)?;
+        return WalIterator::open(storage.dir(), &self.wal_readers, since);
+    }
+
+    /// Subscribes to every write committed to a data directory after sequence number `from_seq`, in order: puts,
+    /// deletes and batches, as well as merges, range deletes and ingests. Subscribing from the last sequence number a
+    /// subscriber handled picks up where it left off, even after a restart, as long as
+    /// [`LSMBuilder::wal_retention`] kept the WALs since.
+    ///
+    /// A write is only committed once it is applied and synced to disk, so that no crash can take back what a
+    /// subscriber has seen: the engine must be built with [`LSMBuilder::wal_sync`].
+    pub fn subscribe(&self, from_seq: u64) -> Result<Subscription> {
+        if !self.wal_sync {
+            return Err(Error::NeedsWalSync);
+        }
+        return Ok(Subscription::new(self.wal_iterator(from_seq)?, from_seq));
+    }
+
     pub fn contains(&mut self, key: &str) -> Result<bool> {
         if !self.bloom_filter.contains(&key) {
+            self.stats.bloom_filter_negatives += 1;
             return Ok(false);
         }
         let maybe_value = self.read(key)?;
@@ -317,6 +1619,30 @@ impl LSMEngine {
     }
 }
 
+fn describe_segments(segments: &[Segment]) -> Result<Vec<SegmentDescription>> {
+    let mut descriptions = Vec::with_capacity(segments.len());
+    for (index, segment) in segments.iter().enumerate() {
+        descriptions.push(SegmentDescription {
+            index,
+            bytes: segment.bytes()?,
+            entries: segment.size(),
+            tombstones: segment.tombstones(),
+            first_key: segment.first_key().map(str::to_owned),
+            last_key: segment.last_key().map(str::to_owned),
+        });
+    }
+    return Ok(descriptions);
+}
+
+/// Refuses a segment file that doesn't match the checksum its manifest recorded, if it recorded one.
+fn check_checksum(segment: &Segment, entry: &SegmentEntry) -> Result<()> {
+    if entry.checksum.is_some_and(|checksum| checksum != segment.checksum()) {
+        let reason = "its contents do not match the checksum in the manifest".to_owned();
+        return Err(Error::Corruption { file: manifest::segment_file_name(entry.file), reason });
+    }
+    return Ok(());
+}
+
 impl Default for LSMEngine {
     fn default() -> Self {
         return LSMBuilder::new().build();
@@ -325,13 +1651,16 @@ impl Default for LSMEngine {
 
 #[cfg(test)]
 mod tests {
-    use crate::{LSMEngine, LSMBuilder};
+    use crate::{LSMEngine, LSMBuilder, MergeOperator, CompactionFilter, Decision, WriteBatch, EventListener, FlushInfo, CompactionInfo, SegmentDescription, RateLimiter, WriteBufferManager, SkipListRep, VectorRep, HashRep, SstWriter, Error, Record, WalReader, SegmentReader, Problem, verify_dir};
+    use std::io::Write;
     
     use rand::seq::SliceRandom;
     use rand::{SeedableRng};
 
     use rand::rngs::StdRng;
     use std::collections::{HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
 
     #[test]
@@ -370,6 +1699,20 @@ mod tests {
         Ok(())
     }
 
+    //the tombstone goes through the WAL once, like any other write
+    #[test]
+    fn test_delete_logged_once() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMEngine::open(dir.path())?;
+        lsm.write("k1".to_owned(), "v1".to_owned())?;
+        lsm.delete("k1")?;
+        let wal = std::fs::read_dir(dir.path())?.map(|entry| entry.unwrap().path()).find(|path| path.extension().is_some_and(|e| e == "log")).unwrap();
+        assert_eq!(std::fs::read_to_string(wal)?.lines().count(), 2);
+        drop(lsm);
+        assert_eq!(LSMEngine::open(dir.path())?.read("k1")?, None);
+        Ok(())
+    }
+
     #[test]
     fn test_reads_on_duplicate_keys() -> std::result::Result<(), Box<dyn std::error::Error>> {
         let mut lsm = LSMBuilder::new().
@@ -453,4 +1796,714 @@ mod tests {
         assert_eq!(lsm.contains("k2")?, false);
         Ok(())
     }
+
+    struct Counter;
+
+    impl MergeOperator for Counter {
+        fn full_merge(&self, _key: &str, existing: Option<&str>, operands: &[String]) -> String {
+            let start: i64 = existing.map_or(0, |v| v.parse().unwrap());
+            let total: i64 = operands.iter().map(|op| op.parse::<i64>().unwrap()).sum();
+            return (start + total).to_string();
+        }
+    }
+
+    #[test]
+    fn test_merge_operator() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new()
+            .segment_size(4)
+            .inmemory_capacity(2)
+            .sparse_offset(1)
+            .wal_path("merge_operator_wal")
+            .merge_operator(Counter)
+            .build();
+        lsm.write("hits".to_owned(), "10".to_owned())?;
+        for k in ["a", "b", "c"] {
+            lsm.write(k.to_owned(), k.to_owned())?;
+        }
+        //"hits" now lives in a segment, so the operands stay pending in the memtable
+        lsm.merge("hits".to_owned(), "1".to_owned())?;
+        lsm.merge("hits".to_owned(), "2".to_owned())?;
+        lsm.merge("misses".to_owned(), "5".to_owned())?;
+        assert_eq!(lsm.read("hits")?, Some("13".to_owned()));
+        assert_eq!(lsm.read("misses")?, Some("5".to_owned()));
+
+        //force a flush, folding the operands during compaction
+        lsm.write("d".to_owned(), "d".to_owned())?;
+        lsm.write("e".to_owned(), "e".to_owned())?;
+        assert_eq!(lsm.read("hits")?, Some("13".to_owned()));
+
+        let mut recovered = LSMBuilder::new().merge_operator(Counter).build();
+        recovered.recover_from(lsm.wal.unwrap().file)?;
+        assert_eq!(recovered.read("hits")?, Some("13".to_owned()));
+        assert_eq!(recovered.read("misses")?, Some("5".to_owned()));
+
+        let mut no_operator = LSMEngine::default();
+        assert!(no_operator.merge("hits".to_owned(), "1".to_owned()).is_err());
+        std::fs::remove_file("merge_operator_wal")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_values() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().open(dir.path())?;
+        //a put whose value would replay as a range delete
+        let forged = crate::range_tombstone::encode_end("z");
+        assert!(matches!(lsm.write("a".to_owned(), forged.clone()), Err(Error::ReservedValue { .. })));
+        let mut batch = WriteBatch::new();
+        batch.put("b".to_owned(), "fine".to_owned());
+        batch.put("c".to_owned(), WriteBatch::new().encode());
+        assert!(matches!(lsm.write_batch(batch), Err(Error::ReservedValue { .. })));
+        let concurrent = crate::ConcurrentEngine::new(LSMBuilder::new().build());
+        assert!(matches!(concurrent.write("a".to_owned(), forged), Err(Error::ReservedValue { .. })));
+
+        lsm.write("k".to_owned(), "v".to_owned())?;
+        drop(lsm);
+        let mut lsm = LSMBuilder::new().open(dir.path())?;
+        assert_eq!(lsm.scan("", "~")?, vec![("k".to_owned(), "v".to_owned())]);
+        Ok(())
+    }
+
+    struct DropTenant(&'static str);
+
+    impl CompactionFilter for DropTenant {
+        fn filter(&self, key: &str, value: &str) -> Decision {
+            if key.starts_with(self.0) {
+                return Decision::Remove;
+            }
+            if value == "stale" {
+                return Decision::ChangeValue("fresh".to_owned());
+            }
+            return Decision::Keep;
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new()
+            .segment_size(10)
+            .inmemory_capacity(2)
+            .sparse_offset(1)
+            .compaction_filter(DropTenant("acme/"))
+            .build();
+        lsm.write("acme/1".to_owned(), "v1".to_owned())?;
+        lsm.write("globex/1".to_owned(), "stale".to_owned())?;
+        lsm.write("globex/2".to_owned(), "v2".to_owned())?;
+        lsm.delete("globex/2")?;
+
+        assert_eq!(lsm.read("acme/1")?, None);
+        assert_eq!(lsm.read("globex/1")?, Some("fresh".to_owned()));
+        assert_eq!(lsm.read("globex/2")?, None);
+        let stats = lsm.last_compaction_stats();
+        assert_eq!((stats.entries_kept, stats.entries_removed, stats.entries_changed), (0, 1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_range() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new()
+            .segment_size(4)
+            .inmemory_capacity(2)
+            .sparse_offset(1)
+            .wal_path("delete_range_wal")
+            .build();
+        for k in ["a", "t/1", "t/2", "t/3", "z"] {
+            lsm.write(k.to_owned(), k.to_owned())?;
+        }
+        lsm.delete_range("t/", "t/~")?;
+        lsm.write("t/2".to_owned(), "again".to_owned())?;
+
+        assert_eq!(lsm.read("t/1")?, None);
+        assert_eq!(lsm.read("t/3")?, None);
+        assert_eq!(lsm.read("t/2")?, Some("again".to_owned()));
+        let expected = vec![("a".to_owned(), "a".to_owned()), ("t/2".to_owned(), "again".to_owned()), ("z".to_owned(), "z".to_owned())];
+        assert_eq!(lsm.scan("a", "zz")?, expected);
+
+        //compaction drops the covered entries for good
+        lsm.write("x".to_owned(), "x".to_owned())?;
+        lsm.write("y".to_owned(), "y".to_owned())?;
+        assert_eq!(lsm.read("t/1")?, None);
+        assert_eq!(lsm.scan("t/", "t/~")?, vec![("t/2".to_owned(), "again".to_owned())]);
+
+        let mut recovered = LSMEngine::default();
+        recovered.recover_from(lsm.wal.unwrap().file)?;
+        assert_eq!(recovered.scan("t/", "t/~")?, vec![("t/2".to_owned(), "again".to_owned())]);
+        std::fs::remove_file("delete_range_wal")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_writes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(2).inmemory_capacity(1).sparse_offset(1).build();
+        assert!(lsm.put_if_absent("k1", "v1".to_owned())?);
+        assert!(!lsm.put_if_absent("k1", "v2".to_owned())?);
+
+        //push k1 out of the memtable and into a segment
+        lsm.write("k2".to_owned(), "v2".to_owned())?;
+        assert!(!lsm.compare_and_swap("k1", Some("v2"), Some("v3".to_owned()))?);
+        assert!(lsm.compare_and_swap("k1", Some("v1"), Some("v3".to_owned()))?);
+        assert_eq!(lsm.read("k1")?, Some("v3".to_owned()));
+
+        assert!(!lsm.delete_if_equals("k2", "v1")?);
+        assert!(lsm.delete_if_equals("k2", "v2")?);
+        assert_eq!(lsm.read("k2")?, None);
+        assert!(lsm.put_if_absent("k2", "v4".to_owned())?);
+        assert_eq!(lsm.read("k2")?, Some("v4".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_batch_recovery() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().wal_path("write_batch_wal").build();
+        lsm.write("k1".to_owned(), "v1".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.put("k2".to_owned(), "v2".to_owned());
+        batch.delete("k1");
+        lsm.write_batch(batch)?;
+
+        let mut recovered = LSMEngine::default();
+        recovered.recover_from(lsm.wal.unwrap().file)?;
+        assert_eq!(recovered.read("k1")?, None);
+        assert_eq!(recovered.read("k2")?, Some("v2".to_owned()));
+        std::fs::remove_file("write_batch_wal")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_get() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(40).inmemory_capacity(10).sparse_offset(4).merge_operator(Counter).build();
+        for i in 0..100 {
+            lsm.write(format!("k{:03}", i), i.to_string())?;
+        }
+        for i in (0..100).step_by(7) {
+            lsm.delete(&format!("k{:03}", i))?;
+        }
+        lsm.delete_range("k050", "k060")?;
+        lsm.merge("k061".to_owned(), "1000".to_owned())?;
+
+        let keys: Vec<String> = vec!["k099", "k003", "missing", "k007", "k055", "k061", "k003", "k000", "zzz"]
+            .into_iter()
+            .map(String::from)
+            .chain((0..100).rev().map(|i| format!("k{:03}", i)))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let values = lsm.multi_get(&keys)?;
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, lsm.read(key)?, "{}", key);
+        }
+        assert_eq!(lsm.multi_get(&["k061", "k055"])?, vec![Some("1061".to_owned()), None]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(4).inmemory_capacity(2).sparse_offset(1).latency_histograms(true).build();
+        for k in ["k1", "k2", "k3"] {
+            lsm.write(k.to_owned(), k.to_owned())?;
+        }
+        lsm.read("k1")?;
+        lsm.read("k3")?;
+
+        let stats = lsm.stats();
+        assert_eq!(stats.writes, 3);
+        assert_eq!(stats.reads, 2);
+        assert_eq!(stats.segments_probed, 1);
+        assert_eq!((stats.memtable_flushes, stats.compactions), (1, 1));
+        assert!(stats.compaction_bytes_written > 0);
+        assert_eq!(stats.read_latency.map(|h| h.count()), Some(2));
+        assert_eq!(stats.write_latency.map(|h| h.count()), Some(3));
+        assert!(LSMEngine::default().stats().read_latency.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(3).inmemory_capacity(2).sparse_offset(2).build();
+        for k in ["k1", "k2", "k3", "k4"] {
+            lsm.write(k.to_owned(), k.to_owned())?;
+        }
+        lsm.delete("k2")?;
+        lsm.write("k5".to_owned(), "k5".to_owned())?;
+        lsm.write("k6".to_owned(), "k6".to_owned())?;
+
+        let description = lsm.describe()?;
+        assert_eq!(description.memtable_entries, 1);
+        assert_eq!(description.memtable_capacity, 2);
+        assert_eq!(description.levels.len(), 1);
+        let level = &description.levels[0];
+        assert_eq!(level.level, 1);
+        assert_eq!(level.segments.len(), 2);
+        assert_eq!(level.entries(), 5);
+        assert_eq!(level.tombstones(), 1);
+        assert_eq!(level.segments[0].first_key.as_deref(), Some("k1"));
+        assert_eq!(level.segments[1].last_key.as_deref(), Some("k5"));
+        assert_eq!(description.sparse_index_entries, 3);
+
+        assert_eq!(lsm.get_property("lsm.num-segments")?, Some("2".to_owned()));
+        assert_eq!(lsm.get_property("lsm.total-segment-bytes")?, Some(level.bytes().to_string()));
+        assert!(lsm.get_property("lsm.levels")?.unwrap().contains("1 1 2 0"));
+        assert_eq!(lsm.get_property("lsm.nonsense")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_trigger() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(10).inmemory_capacity(3).compaction_trigger(3).merge_operator(Counter).build();
+        for i in 0..6 {
+            lsm.write(format!("k{}", i), i.to_string())?;
+        }
+        lsm.delete("k1")?;
+        lsm.merge("k2".to_owned(), "10".to_owned())?;
+        lsm.delete_range("k3", "k4")?;
+        lsm.write("k0".to_owned(), "new".to_owned())?;
+        lsm.merge("k2".to_owned(), "100".to_owned())?;
+
+        assert_eq!(lsm.get_property("lsm.num-unmerged-segments")?, Some("2".to_owned()));
+        assert_ne!(lsm.get_property("lsm.pending-compaction-bytes")?, Some("0".to_owned()));
+        let expected = vec![
+            Some("new".to_owned()), None, Some("112".to_owned()), None, Some("4".to_owned()), Some("5".to_owned()),
+        ];
+        let keys = ["k0", "k1", "k2", "k3", "k4", "k5"];
+        for (key, value) in keys.iter().zip(expected.iter()) {
+            assert_eq!(&lsm.read(key)?, value, "{}", key);
+        }
+        assert_eq!(lsm.multi_get(&keys)?, expected);
+        let scanned = lsm.scan("k0", "k9")?.into_iter().map(|(_key, value)| Some(value)).collect::<Vec<_>>();
+        assert_eq!(scanned, expected.iter().filter(|value| value.is_some()).cloned().collect::<Vec<_>>());
+
+        lsm.compact()?;
+        assert_eq!(lsm.get_property("lsm.num-unmerged-segments")?, Some("0".to_owned()));
+        assert_eq!(lsm.get_property("lsm.num-entries-memtable")?, Some("0".to_owned()));
+        assert_eq!(lsm.multi_get(&keys)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_stalls() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let limiter = RateLimiter::new(1_000_000);
+        let mut lsm = LSMBuilder::new()
+            .segment_size(10)
+            .inmemory_capacity(2)
+            .compaction_trigger(10)
+            .slowdown_writes_trigger(1)
+            .stop_writes_trigger(2)
+            .delayed_write_rate(1000)
+            .rate_limiter(limiter.clone())
+            .build();
+        for i in 0..5 {
+            lsm.write(format!("k{}", i), i.to_string())?;
+        }
+        //k2 flushed the first segment, after which k3 and k4 were delayed
+        assert_eq!(lsm.stats().write_slowdowns, 2);
+        assert!(lsm.stats().write_stall_time >= Duration::from_millis(4));
+        assert!(limiter.total_bytes() > 0);
+
+        //k4 flushed the second segment, so the next write waits for both to be merged
+        lsm.write("k5".to_owned(), "5".to_owned())?;
+        let stats = lsm.stats();
+        assert_eq!((stats.write_stops, stats.compactions), (1, 1));
+        assert_eq!(lsm.get_property("lsm.num-unmerged-segments")?, Some("0".to_owned()));
+        assert_eq!(lsm.read("k0")?, Some("0".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_buffer_size() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().write_buffer_size(4096).build();
+        for i in 0..3 {
+            lsm.write(format!("k{}", i), "v".repeat(3000))?;
+        }
+        //the second value overflows the buffer, so the third write flushes both
+        assert_eq!(lsm.stats().memtable_flushes, 1);
+        assert_eq!(lsm.get_property("lsm.num-entries-memtable")?, Some("1".to_owned()));
+        let bytes: usize = lsm.get_property("lsm.memtable-bytes")?.unwrap().parse()?;
+        assert!(bytes > 3000);
+
+        let manager = WriteBufferManager::new(2000);
+        let mut first = LSMBuilder::new().write_buffer_manager(manager.clone()).build();
+        let mut second = LSMBuilder::new().write_buffer_manager(manager.clone()).build();
+        first.write("k1".to_owned(), "v".repeat(1500))?;
+        second.write("k1".to_owned(), "v".repeat(10))?;
+        assert!(manager.memory_usage() < 2000);
+        first.write("k2".to_owned(), "v".repeat(1000))?;
+        //the budget is spent, so whichever engine writes next flushes
+        second.write("k2".to_owned(), "v".repeat(10))?;
+        assert_eq!((first.stats().memtable_flushes, second.stats().memtable_flushes), (0, 1));
+        assert_eq!(second.read("k1")?, Some("v".repeat(10)));
+        drop(first);
+        assert!(manager.memory_usage() < 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memtable_reps() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let builder = || LSMBuilder::new().segment_size(20).inmemory_capacity(8).sparse_offset(3).merge_operator(Counter);
+        let mut engines = [
+            builder().build(),
+            builder().memtable_rep(SkipListRep::default()).build(),
+            builder().memtable_rep(VectorRep::default()).build(),
+            builder().memtable_rep(HashRep::default()).build(),
+        ];
+        for lsm in engines.iter_mut() {
+            for i in (0..50).rev() {
+                lsm.write(format!("k{:02}", i), i.to_string())?;
+            }
+            lsm.delete("k07")?;
+            lsm.delete_range("k10", "k15")?;
+            lsm.merge("k20".to_owned(), "100".to_owned())?;
+            lsm.write("k49".to_owned(), "last".to_owned())?;
+        }
+        let expected = engines[0].scan("k00", "k99")?;
+        assert_eq!(expected.len(), 44);
+        for lsm in engines.iter_mut() {
+            assert_eq!(lsm.scan("k00", "k99")?, expected);
+            assert_eq!(lsm.read("k20")?, Some("120".to_owned()));
+            assert_eq!(lsm.read("k49")?, Some("last".to_owned()));
+            assert_eq!(lsm.read("k12")?, None);
+        }
+        Ok(())
+    }
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl EventListener for Recorder {
+        fn on_flush_begin(&self, entries: usize) {
+            self.0.lock().unwrap().push(format!("flush begin {}", entries));
+        }
+        fn on_flush_completed(&self, info: &FlushInfo) {
+            self.0.lock().unwrap().push(format!("flush completed {}", info.entries));
+        }
+        fn on_compaction_begin(&self, input: &[SegmentDescription]) {
+            self.0.lock().unwrap().push(format!("compaction begin {}", input.len()));
+        }
+        fn on_compaction_completed(&self, info: &CompactionInfo) {
+            let entries: usize = info.output.iter().map(|s| s.entries).sum();
+            self.0.lock().unwrap().push(format!("compaction completed {} {}", info.input.len(), entries));
+        }
+        fn on_wal_rotated(&self) {
+            self.0.lock().unwrap().push("wal rotated".to_owned());
+        }
+    }
+
+    #[test]
+    fn test_event_listener() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut lsm = LSMBuilder::new().segment_size(4).inmemory_capacity(2).event_listener(Recorder(events.clone())).build();
+        for k in ["k1", "k2", "k3", "k4", "k5"] {
+            lsm.write(k.to_owned(), k.to_owned())?;
+        }
+        let expected = vec![
+            "flush begin 2", "flush completed 2", "compaction begin 1", "compaction completed 1 2",
+            "flush begin 2", "flush completed 2", "compaction begin 2", "compaction completed 2 4",
+        ];
+        assert_eq!(*events.lock().unwrap(), expected);
+
+        lsm.recover_from(tempfile::tempfile()?)?;
+        assert_eq!(events.lock().unwrap().last().map(String::as_str), Some("wal rotated"));
+        Ok(())
+    }
+
+    fn external_file(path: &std::path::Path, entries: &[(&str, Option<&str>)]) -> crate::Result<()> {
+        let mut writer = SstWriter::create(path)?;
+        for (key, value) in entries {
+            match value {
+                Some(value) => writer.put(key.to_string(), value.to_string())?,
+                None => writer.delete(key)?,
+            }
+        }
+        assert_eq!(writer.finish()?.entries, entries.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingest_external_files() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let wal = dir.path().join("wal");
+        let mut lsm = LSMBuilder::new().segment_size(4).inmemory_capacity(2).sparse_offset(2).compaction_trigger(5).wal_path(&wal).build();
+        for k in ["b1", "b2", "b3", "d1", "d2"] {
+            lsm.write(k.to_owned(), k.to_owned())?;
+        }
+        lsm.compact()?;
+        lsm.write("e1".to_owned(), "e1".to_owned())?;
+
+        //overlaps nothing, so it goes straight into the sorted run
+        external_file(&dir.path().join("a"), &[("a1", Some("1")), ("a2", Some("2")), ("a3", Some("3"))])?;
+        //overlaps the run, so it goes into level 0
+        external_file(&dir.path().join("c"), &[("b2", Some("new")), ("b3", None), ("c1", Some("1"))])?;
+        //overlaps the memtable, which gets flushed first
+        external_file(&dir.path().join("e"), &[("e1", Some("new"))])?;
+        lsm.ingest_external_files(&[dir.path().join("e"), dir.path().join("a"), dir.path().join("c")])?;
+
+        let described = lsm.describe()?;
+        assert_eq!(described.levels[0].segments.len(), 3);
+        assert_eq!(described.levels[1].segments.len(), 3);
+        assert_eq!(described.memtable_entries, 0);
+        let expected = vec![
+            ("a1", "1"), ("a2", "2"), ("a3", "3"), ("b1", "b1"), ("b2", "new"), ("c1", "1"), ("d1", "d1"), ("d2", "d2"), ("e1", "new"),
+        ];
+        let expected = expected.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect::<Vec<_>>();
+        assert_eq!(lsm.scan("a", "z")?, expected);
+        for (key, value) in expected.iter() {
+            assert_eq!(lsm.read(key)?.as_ref(), Some(value), "{}", key);
+        }
+        assert!(lsm.contains("a2")?);
+        assert_eq!(lsm.read("b3")?, None);
+
+        lsm.compact()?;
+        assert_eq!(lsm.scan("a", "z")?, expected);
+        let mut recovered = LSMBuilder::new().build();
+        recovered.recover_from(std::fs::File::open(&wal)?)?;
+        assert_eq!(recovered.scan("a", "z")?, expected);
+
+        //nothing is ingested unless every file is valid
+        external_file(&dir.path().join("f"), &[("f1", Some("1")), ("f3", Some("3"))])?;
+        external_file(&dir.path().join("g"), &[("f2", Some("2"))])?;
+        let result = lsm.ingest_external_files(&[dir.path().join("f"), dir.path().join("g")]);
+        assert!(matches!(result, Err(Error::IngestFailed { .. })));
+        assert_eq!(lsm.read("f1")?, None);
+
+        let mut writer = SstWriter::create(dir.path().join("h"))?;
+        writer.put("k2".to_owned(), "2".to_owned())?;
+        assert!(writer.put("k1".to_owned(), "1".to_owned()).is_err());
+        assert!(writer.put("k2".to_owned(), "2".to_owned()).is_err());
+        //nor can they pass internal encodings off as values
+        assert!(matches!(writer.put("k3".to_owned(), crate::merge::encode_operands(&["1".to_owned()])), Err(Error::IngestFailed { .. })));
+        let forged = serde_json::to_string(&crate::kv::KVPair { key: "k1".to_owned(), value: WriteBatch::new().encode() })?;
+        std::fs::write(dir.path().join("i"), forged + "\n")?;
+        assert!(matches!(lsm.ingest_external_files(&[dir.path().join("i")]), Err(Error::IngestFailed { .. })));
+
+        //transactions that read an ingested key conflict with the ingest
+        let mut txn = lsm.begin_transaction();
+        txn.get(&mut lsm, "j2")?;
+        external_file(&dir.path().join("j"), &[("j1", Some("1")), ("j2", Some("2"))])?;
+        lsm.ingest_external_files(&[dir.path().join("j")])?;
+        txn.put("j2".to_owned(), "txn".to_owned());
+        assert!(matches!(txn.commit(&mut lsm), Err(Error::TransactionConflict { .. })));
+        Ok(())
+    }
+
+    fn fill(lsm: &mut LSMEngine) -> crate::Result<()> {
+        for i in 0..20 {
+            lsm.write(format!("k{:02}", i), i.to_string())?;
+        }
+        lsm.delete("k03")?;
+        lsm.merge("k04".to_owned(), "10".to_owned())?;
+        lsm.delete_range("k10", "k13")?;
+        let mut batch = WriteBatch::new();
+        batch.put("k15".to_owned(), "batch".to_owned());
+        batch.delete("k16");
+        lsm.write_batch(batch)?;
+        lsm.merge("k05".to_owned(), "10".to_owned())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_data_dir() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let builder = || LSMBuilder::new().segment_size(6).inmemory_capacity(3).sparse_offset(2).compaction_trigger(2).merge_operator(Counter);
+        let mut lsm = builder().open(dir.path())?;
+        fill(&mut lsm)?;
+        let expected = lsm.scan("k", "l")?;
+        assert_eq!(expected.len(), 15);
+        drop(lsm);
+
+        let mut lsm = builder().open(dir.path())?;
+        assert_eq!(lsm.scan("k", "l")?, expected);
+        assert_eq!(lsm.read("k04")?, Some("14".to_owned()));
+        assert!(lsm.contains("k19")?);
+        lsm.write("k20".to_owned(), "20".to_owned())?;
+        lsm.compact()?;
+        drop(lsm);
+        //files the engine didn't name are not its to delete
+        for name in ["notes.log", "1.sst", "backup.tmp"] {
+            std::fs::write(dir.path().join(name), "not the engine's")?;
+        }
+
+        let mut lsm = builder().open(dir.path())?;
+        assert_eq!(lsm.read("k20")?, Some("20".to_owned()));
+        assert_eq!(lsm.scan("k", "k20")?, expected);
+        //only the manifest, the WAL, the live segments and the files that aren't the engine's are left
+        let files = std::fs::read_dir(dir.path())?.count();
+        assert_eq!(files, 5 + lsm.describe()?.levels.iter().map(|level| level.segments.len()).sum::<usize>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let builder = || LSMBuilder::new().segment_size(6).inmemory_capacity(3).compaction_trigger(3).merge_operator(Counter);
+        let mut lsm = builder().open(dir.path().join("db"))?;
+        fill(&mut lsm)?;
+        lsm.delete_range("k00", "k02")?;
+        let expected = lsm.scan("k", "l")?;
+        lsm.checkpoint(dir.path().join("checkpoint"))?;
+        lsm.write("k00".to_owned(), "after".to_owned())?;
+        lsm.compact()?;
+
+        let mut restored = builder().open(dir.path().join("checkpoint"))?;
+        assert_eq!(restored.scan("k", "l")?, expected);
+        assert_eq!(lsm.read("k00")?, Some("after".to_owned()));
+        assert!(lsm.checkpoint(dir.path().join("checkpoint")).is_err());
+
+        //an engine without a data directory copies its segments out
+        let mut in_memory = LSMBuilder::new().segment_size(6).inmemory_capacity(3).merge_operator(Counter).build();
+        fill(&mut in_memory)?;
+        in_memory.checkpoint(dir.path().join("copy"))?;
+        let mut restored = builder().open(dir.path().join("copy"))?;
+        assert_eq!(restored.scan("k", "l")?, in_memory.scan("k", "l")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_readers() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut lsm = LSMBuilder::new().merge_operator(Counter).open(dir.path())?;
+        lsm.write("k2".to_owned(), "v2".to_owned())?;
+        lsm.merge("k1".to_owned(), "1".to_owned())?;
+        lsm.delete("k2")?;
+        lsm.delete_range("k3", "k5")?;
+        let mut batch = WriteBatch::new();
+        batch.put("k3".to_owned(), "v3".to_owned());
+        batch.delete("k4");
+        lsm.write_batch(batch)?;
+
+        let paths = || -> std::io::Result<Vec<_>> { Ok(std::fs::read_dir(dir.path())?.map(|entry| entry.unwrap().path()).collect()) };
+        let wal = paths()?.into_iter().find(|path| path.extension().is_some_and(|e| e == "log")).unwrap();
+        let records = WalReader::open(wal)?.collect::<crate::Result<Vec<_>>>()?;
+        let put = |key: &str| Record::Put { key: key.to_owned(), value: key.replace('k', "v") };
+        let delete = |key: &str| Record::Delete { key: key.to_owned() };
+        assert_eq!(records, vec![
+            put("k2"),
+            Record::Merge { key: "k1".to_owned(), operands: vec!["1".to_owned()] },
+            delete("k2"),
+            Record::DeleteRange { start: "k3".to_owned(), end: "k5".to_owned() },
+            Record::Batch(vec![put("k3"), delete("k4")]),
+        ]);
+
+        lsm.compact()?;
+        let segment = paths()?.into_iter().find(|path| path.extension().is_some_and(|e| e == "sst")).unwrap();
+        let entries = SegmentReader::open(segment)?.map(|entry| entry.map(|(_offset, record)| record)).collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(entries, vec![Record::Put { key: "k1".to_owned(), value: "1".to_owned() }, delete("k2"), put("k3"), delete("k4")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let builder = || LSMBuilder::new().segment_size(10).inmemory_capacity(10).sparse_offset(3).compaction_trigger(100);
+        let mut lsm = builder().open(dir.path())?;
+        for i in 0..60 {
+            if i == 50 {
+                lsm.compact()?;
+            }
+            lsm.write(format!("k{:02}", i), i.to_string())?;
+        }
+        assert_eq!(lsm.verify()?, vec![]);
+        assert_eq!(verify_dir(dir.path())?, vec![]);
+
+        //the in-memory structures, broken every way at once
+        let (key, _entry) = lsm.sparse_memory_index.iter().nth(1).map(|(key, entry)| (key.clone(), *entry)).unwrap();
+        lsm.sparse_memory_index.insert(key.clone(), (1, 0));
+        lsm.sparse_memory_index.insert("k".to_owned(), (0, 99));
+        lsm.bloom_filter = bloom::BloomFilter::with_rate(0.0001, 100);
+        let problems = lsm.verify()?;
+        assert!(problems.contains(&Problem::BadIndexEntry { key: "k".to_owned(), reason: "segment 99 is past the end of the sorted run".to_owned() }));
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::BadIndexEntry { key: found, .. } if *found == key)));
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::BloomMiss { .. })));
+        drop(lsm);
+
+        //a segment file, damaged and put out of order, along with a stray file
+        let segment = std::fs::read_dir(dir.path())?.map(|entry| entry.unwrap().path()).filter(|path| path.extension().is_some_and(|e| e == "sst")).min().unwrap();
+        let contents = std::fs::read_to_string(&segment)?;
+        let mut lines = contents.lines().collect::<Vec<_>>();
+        lines.swap(0, 1);
+        lines.insert(2, "not json");
+        std::fs::write(&segment, lines.join("\n") + "\n")?;
+        std::fs::write(dir.path().join("999999.sst"), "")?;
+        let problems = verify_dir(dir.path())?;
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::Unsorted { .. })));
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::Unreadable { offset, .. } if *offset > 0)));
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::ChecksumMismatch { .. })));
+        assert!(problems.contains(&Problem::Manifest("999999.sst is not listed".to_owned())));
+        std::fs::remove_file(dir.path().join("999999.sst"))?;
+        assert!(matches!(builder().open(dir.path()), Err(Error::SstError(_)) | Err(Error::KvError(_))));
+
+        //still in order and parseable, but not what was written
+        std::fs::write(&segment, contents.replacen("\"value\":\"", "\"value\":\"x", 1))?;
+        let problems = verify_dir(dir.path())?;
+        assert!(matches!(problems.as_slice(), [Problem::ChecksumMismatch { .. }]));
+        assert!(matches!(builder().open(dir.path()), Err(Error::Corruption { .. })));
+
+        //manifests from before checksums were recorded have nothing to check against
+        let path = dir.path().join("MANIFEST");
+        let mut manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        for level in ["level0", "run"] {
+            for entry in manifest[level].as_array_mut().unwrap() {
+                entry.as_object_mut().unwrap().remove("checksum");
+            }
+        }
+        std::fs::write(&path, manifest.to_string())?;
+        assert_eq!(verify_dir(dir.path())?, vec![]);
+        builder().open(dir.path())?;
+        assert_eq!(verify_dir(dir.path())?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let builder = || LSMBuilder::new().segment_size(10).inmemory_capacity(10).compaction_trigger(100);
+        let mut lsm = builder().open(dir.path())?;
+        for i in 0..44 {
+            lsm.write(format!("k{:02}", i), i.to_string())?;
+        }
+        drop(lsm);
+        let files = |extension: &str| -> std::io::Result<Vec<_>> {
+            let paths = std::fs::read_dir(dir.path())?.map(|entry| entry.unwrap().path());
+            let mut paths = paths.filter(|path| path.extension().is_some_and(|e| e == extension)).collect::<Vec<_>>();
+            paths.sort();
+            Ok(paths)
+        };
+
+        //a damaged segment and a torn WAL record, then no manifest at all
+        let segment = files("sst")?[1].clone();
+        let mut lines = std::fs::read_to_string(&segment)?.lines().map(str::to_owned).collect::<Vec<_>>();
+        lines[3] = "not json".to_owned();
+        std::fs::write(&segment, lines.join("\n") + "\n")?;
+        let mut wal = std::fs::OpenOptions::new().append(true).open(&files("log")?[0])?;
+        wal.write_all(b"{\"key\":\"k9")?;
+        std::fs::remove_file(dir.path().join("MANIFEST"))?;
+
+        let report = LSMEngine::repair(dir.path())?;
+        assert!(report.manifest_lost);
+        assert_eq!((report.segments, report.entries, report.records), (4, 39, 4));
+        assert_eq!(report.lost.len(), 2);
+        assert!(matches!(&report.lost[0], Problem::Unreadable { offset, .. } if *offset > 0));
+        assert_eq!(verify_dir(dir.path())?, vec![]);
+        let mut lsm = builder().open(dir.path())?;
+        assert_eq!(lsm.scan("k", "l")?.len(), 43);
+        assert_eq!(lsm.read("k13")?, None);
+        assert_eq!(lsm.read("k43")?, Some("43".to_owned()));
+        lsm.write("m".to_owned(), "logged".to_owned())?;
+        let issued = lsm.latest_sequence();
+        drop(lsm);
+
+        //with the manifest intact, a segment that fails its checksum keeps the engine from opening until repaired
+        let segment = files("sst")?[0].clone();
+        std::fs::write(&segment, std::fs::read_to_string(&segment)?.replacen("\"0\"", "\"zero\"", 1))?;
+        assert!(matches!(builder().open(dir.path()), Err(Error::Corruption { .. })));
+        let report = LSMEngine::repair(dir.path())?;
+        assert!(!report.manifest_lost);
+        assert!(matches!(report.lost.as_slice(), [Problem::ChecksumMismatch { .. }]));
+        let mut lsm = builder().wal_retention(1).open(dir.path())?;
+        assert_eq!(lsm.read("k00")?, Some("zero".to_owned()));
+        assert_eq!(lsm.scan("k", "l")?.len(), 43);
+
+        //the records kept are renumbered past everything issued before
+        assert!(report.first_sequence > issued);
+        assert!(matches!(lsm.wal_iterator(issued - 1), Err(Error::SequenceUnavailable { .. })));
+        let mut records = lsm.wal_iterator(report.first_sequence - 1)?;
+        assert_eq!(records.next().transpose()?.map(|(sequence, _)| sequence), Some(report.first_sequence));
+        Ok(())
+    }
 }
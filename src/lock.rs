@@ -0,0 +1,124 @@
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+pub(crate) type TransactionId = u64;
+
+#[derive(Default)]
+struct LockState {
+    owners: HashMap<String, TransactionId>,
+    // the edges of the wait-for graph: a blocked transaction and the one holding the lock it wants
+    waiting_for: HashMap<TransactionId, TransactionId>,
+}
+
+impl LockState {
+    fn would_deadlock(&self, txn: TransactionId, owner: TransactionId) -> bool {
+        let mut current = owner;
+        loop {
+            if current == txn {
+                return true;
+            }
+            match self.waiting_for.get(&current) {
+                Some(next) => current = *next,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Exclusive per-key locks shared by every pessimistic transaction of an engine.
+#[derive(Default)]
+pub(crate) struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+    next_id: AtomicU64,
+}
+
+impl LockManager {
+    pub fn next_id(&self) -> TransactionId {
+        return self.next_id.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Blocks until `txn` holds the lock on `key`, failing if waiting would deadlock or takes longer than `timeout`.
+    pub fn lock(&self, txn: TransactionId, key: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let owner = match state.owners.get(key) {
+                Some(owner) if *owner == txn => return Ok(()),
+                Some(owner) => *owner,
+                None => {
+                    state.waiting_for.remove(&txn);
+                    state.owners.insert(key.to_owned(), txn);
+                    return Ok(());
+                }
+            };
+            if state.would_deadlock(txn, owner) {
+                state.waiting_for.remove(&txn);
+                return Err(Error::Deadlock { key: key.to_owned() });
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting_for.remove(&txn);
+                return Err(Error::LockTimeout { key: key.to_owned() });
+            }
+            state.waiting_for.insert(txn, owner);
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    pub fn unlock<'a, I: IntoIterator<Item = &'a String>>(&self, txn: TransactionId, keys: I) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for key in keys {
+            if state.owners.get(key) == Some(&txn) {
+                state.owners.remove(key);
+            }
+        }
+        self.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_lock_timeout() {
+        let locks = LockManager::default();
+        locks.lock(1, "k1", Duration::from_millis(10)).unwrap();
+        locks.lock(1, "k1", Duration::from_millis(10)).unwrap();
+        let result = locks.lock(2, "k1", Duration::from_millis(10));
+        assert!(matches!(result, Err(Error::LockTimeout { .. })));
+
+        locks.unlock(1, &["k1".to_owned()]);
+        locks.lock(2, "k1", Duration::from_millis(10)).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let locks = Arc::new(LockManager::default());
+        locks.lock(1, "k1", Duration::from_secs(5)).unwrap();
+        locks.lock(2, "k2", Duration::from_secs(5)).unwrap();
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(1, "k2", Duration::from_secs(5)))
+        };
+        while !locks.state.lock().unwrap().waiting_for.contains_key(&1) {
+            thread::yield_now();
+        }
+
+        let result = locks.lock(2, "k1", Duration::from_secs(5));
+        assert!(matches!(result, Err(Error::Deadlock { .. })));
+        locks.unlock(2, &["k2".to_owned()]);
+        assert!(waiter.join().unwrap().is_ok());
+    }
+}
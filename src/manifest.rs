@@ -0,0 +1,201 @@
+use crate::range_tombstone::RangeTombstone;
+use crate::sst::{self, Segment};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub(crate) const MANIFEST: &str = "MANIFEST";
+
+/// The engine's state in a data directory: the segment files making up each level, the range tombstones no merge
+/// has applied yet, and the WAL holding everything written since.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub next_file: u64,
+    pub wal: Option<u64>,
+    //the sequence number of the WAL's first record
+    #[serde(default)]
+    pub wal_start: u64,
+    //older WALs kept for replication, oldest first
+    #[serde(default)]
+    pub archived_wals: Vec<WalEntry>,
+    //oldest first
+    pub level0: Vec<SegmentEntry>,
+    pub run: Vec<SegmentEntry>,
+    pub range_tombstones: Vec<TombstoneEntry>,
+}
+
+//timestamps don't survive a restart, so segments and range tombstones are ordered by a shared sequence instead
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SegmentEntry {
+    pub file: u64,
+    pub sequence: usize,
+    //unknown for segments listed before manifests recorded checksums, which go unchecked
+    #[serde(default)]
+    pub checksum: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WalEntry {
+    pub file: u64,
+    pub start: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TombstoneEntry {
+    pub start: String,
+    pub end: String,
+    pub sequence: usize,
+}
+
+impl Manifest {
+    /// Records the segments, given along with their file numbers, and the range tombstones.
+    pub fn capture(level0: &[(u64, &Segment)], run: &[(u64, &Segment)], range_tombstones: &[RangeTombstone]) -> Manifest {
+        let mut timestamps = level0.iter().chain(run.iter()).map(|(_file, segment)| segment.timestamp()).collect::<Vec<_>>();
+        timestamps.extend(range_tombstones.iter().map(|t| t.created_at));
+        timestamps.sort();
+        timestamps.dedup();
+        let sequence = |at: Instant| timestamps.binary_search(&at).unwrap();
+        let entries = |segments: &[(u64, &Segment)]| {
+            segments
+                .iter()
+                .map(|(file, segment)| SegmentEntry { file: *file, sequence: sequence(segment.timestamp()), checksum: Some(segment.checksum()) })
+                .collect()
+        };
+        return Manifest {
+            next_file: 0,
+            wal: None,
+            wal_start: 0,
+            archived_wals: vec![],
+            level0: entries(level0),
+            run: entries(run),
+            range_tombstones: range_tombstones
+                .iter()
+                .map(|t| TombstoneEntry { start: t.start.clone(), end: t.end.clone(), sequence: sequence(t.created_at) })
+                .collect(),
+        };
+    }
+
+    /// Maps sequences back to timestamps, all of them in the past and in the same order.
+    pub fn timestamps(&self) -> impl Fn(usize) -> Instant {
+        let sequences = self.level0.iter().chain(self.run.iter()).map(|entry| entry.sequence);
+        let newest = sequences.chain(self.range_tombstones.iter().map(|t| t.sequence)).max().unwrap_or(0);
+        let now = Instant::now();
+        return move |sequence| now - Duration::from_micros((newest - sequence) as u64 + 1);
+    }
+
+    /// Every WAL kept, oldest first, with the sequence number of each one's first record.
+    pub fn wals(&self) -> Vec<WalEntry> {
+        let mut wals = self.archived_wals.clone();
+        wals.extend(self.wal.map(|file| WalEntry { file, start: self.wal_start.max(1) }));
+        return wals;
+    }
+
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let path = dir.join(MANIFEST);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let manifest = serde_json::from_reader(File::open(path)?)?;
+        return Ok(Some(manifest));
+    }
+
+    /// Replaces the manifest in `dir` atomically, so a crash leaves either the old one or the new one behind.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let temp = dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&temp, dir.join(MANIFEST))?;
+        File::open(dir)?.sync_all()?;
+        return Ok(());
+    }
+}
+
+pub(crate) fn segment_file_name(number: u64) -> String {
+    return format!("{:06}.sst", number);
+}
+
+pub(crate) fn wal_file_name(number: u64) -> String {
+    return format!("{:06}.log", number);
+}
+
+/// The number of a segment or WAL file, if `name` is exactly what the engine would have named it.
+pub(crate) fn file_number(name: &str) -> Option<u64> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    let number = stem.parse().ok()?;
+    let ours = match extension {
+        "sst" => segment_file_name(number),
+        "log" => wal_file_name(number),
+        _ => return None,
+    };
+    return (ours == name).then_some(number);
+}
+
+/// A data directory, handing out numbers for new segment and WAL files. Clones share the numbering.
+#[derive(Clone)]
+pub(crate) struct Storage {
+    dir: PathBuf,
+    next_file: Arc<AtomicU64>,
+}
+
+impl Storage {
+    pub fn new(dir: &Path, next_file: u64) -> Self {
+        return Storage { dir: dir.to_path_buf(), next_file: Arc::new(AtomicU64::new(next_file)) };
+    }
+
+    pub fn dir(&self) -> &Path {
+        return &self.dir;
+    }
+
+    pub fn next_file(&self) -> u64 {
+        return self.next_file.load(Ordering::SeqCst);
+    }
+
+    pub fn segment_path(&self, number: u64) -> PathBuf {
+        return self.dir.join(segment_file_name(number));
+    }
+
+    pub fn wal_path(&self, number: u64) -> PathBuf {
+        return self.dir.join(wal_file_name(number));
+    }
+
+    pub fn new_segment(&self) -> sst::Result<Segment> {
+        let number = self.next_file.fetch_add(1, Ordering::SeqCst);
+        return Segment::create(&self.segment_path(number), number);
+    }
+
+    pub fn new_wal(&self) -> Result<(u64, File)> {
+        let number = self.next_file.fetch_add(1, Ordering::SeqCst);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.wal_path(number))?;
+        return Ok((number, file));
+    }
+
+    /// Deletes the segment and WAL files `manifest` doesn't list, left behind by a crash before it was saved. Files
+    /// the engine didn't name itself are left alone, whatever their extension.
+    pub fn remove_orphans(&self, manifest: &Manifest) -> Result<()> {
+        let mut live = manifest.level0.iter().chain(manifest.run.iter()).map(|entry| segment_file_name(entry.file)).collect::<Vec<_>>();
+        live.extend(manifest.wal.iter().chain(manifest.archived_wals.iter().map(|entry| &entry.file)).map(|number| wal_file_name(*number)));
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let ours = file_number(&name).is_some() || name == format!("{}.tmp", MANIFEST);
+            if ours && !live.contains(&name) {
+                fs::remove_file(self.dir.join(name))?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// A new segment: a numbered file in the data directory, if there is one, and an anonymous temporary file otherwise.
+pub(crate) fn new_segment(storage: Option<&Storage>) -> sst::Result<Segment> {
+    return match storage {
+        Some(storage) => storage.new_segment(),
+        None => Ok(Segment::temp()),
+    };
+}
@@ -1,47 +1,161 @@
-use std::collections::BTreeMap;
-use std::collections::btree_map::IntoIter;
-use std::hash::Hash;
-use std::borrow::Borrow;
+use crate::memtable_rep::{BTreeRep, MemtableRep};
+use crate::write_buffer::WriteBufferManager;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub struct Memtable<K: PartialOrd + Hash + Ord, T> {
-    kv_table: BTreeMap<K, T>,
+pub struct Memtable {
+    rep: Box<dyn MemtableRep>,
     capacity: usize,
+    //atomic so that concurrent writers can charge their entries through a shared reference
+    bytes: AtomicUsize,
+    write_buffer_size: Option<usize>,
+    write_buffer_manager: Option<WriteBufferManager>,
 }
 
-impl<K: PartialOrd + Hash + Ord, T> Memtable<K, T> {
+impl Memtable {
     pub fn new(capacity: usize) -> Self {
         Memtable {
-            kv_table: BTreeMap::new(),
+            rep: Box::new(BTreeRep::default()),
             capacity: capacity,
+            bytes: AtomicUsize::new(0),
+            write_buffer_size: None,
+            write_buffer_manager: None,
         }
     }
 
+    /// Stores the entries in `rep` rather than the default B-tree.
+    pub fn with_rep(mut self, rep: Box<dyn MemtableRep>) -> Self {
+        self.release(self.bytes());
+        self.rep = rep;
+        self
+    }
+
+    /// Also caps the memtable at `write_buffer_size` bytes, on top of the entry count.
+    pub fn with_write_buffer_size(mut self, write_buffer_size: Option<usize>) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// Charges the memtable's entries to `manager` as well.
+    pub fn with_write_buffer_manager(mut self, manager: Option<WriteBufferManager>) -> Self {
+        self.write_buffer_manager = manager;
+        self
+    }
+
+    /// The memory `key` and `value` take up in the memtable, including their share of the rep's nodes.
+    fn entry_size(&self, key: &str, value: &str) -> usize {
+        key.len() + value.len() + self.rep.entry_overhead()
+    }
+
+    fn charge(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(manager) = self.write_buffer_manager.as_ref() {
+            manager.reserve(bytes);
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(manager) = self.write_buffer_manager.as_ref() {
+            manager.free(bytes);
+        }
+    }
 
-    pub fn insert(&mut self, key: K, value: T) {
-        self.kv_table.insert(key, value);
+    pub fn insert(&mut self, key: String, value: String) {
+        self.charge(self.entry_size(&key, &value));
+        if let Some((old_key, old_value)) = self.rep.insert(key, value) {
+            self.release(self.entry_size(&old_key, &old_value));
+        }
     }
 
-    pub fn contains<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Ord, {
-        return self.kv_table.contains_key(key);
+    /// Whether several writers may [`insert_concurrently`](Memtable::insert_concurrently).
+    pub fn allows_concurrent_insert(&self) -> bool {
+        self.rep.as_concurrent().is_some()
     }
 
+    /// Inserts through a shared reference, alongside other writers. Only valid if the rep allows it, and if no other
+    /// writer is inserting the same key at the same time.
+    pub fn insert_concurrently(&self, key: String, value: String) {
+        let rep = self.rep.as_concurrent().expect("concurrent inserts are only made into reps that allow them");
+        self.charge(self.entry_size(&key, &value));
+        if let Some((old_key, old_value)) = rep.insert_concurrently(key, value) {
+            self.release(self.entry_size(&old_key, &old_value));
+        }
+    }
 
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&T> where K: Borrow<Q>, Q: Ord, {
-        self.kv_table.get(key)
+    pub fn contains(&self, key: &str) -> bool {
+        return self.rep.get(key).is_some();
     }
 
 
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.rep.get(key)
+    }
+
+
+    /// Entries with keys in `start..end`, in key order.
+    pub fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = (String, String)> + 'a> {
+        self.rep.range(start, end)
+    }
+
+    pub fn remove_range(&mut self, start: &str, end: &str) {
+        for (key, value) in self.rep.remove_range(start, end) {
+            self.release(self.entry_size(&key, &value));
+        }
+    }
+
     pub fn clear(&mut self) {
-        self.kv_table.clear();
+        drop(self.rep.drain());
+        self.release(self.bytes());
+    }
+
+
+    pub fn drain(&mut self) -> Box<dyn Iterator<Item = (String, String)>> {
+        self.release(self.bytes());
+        self.rep.drain()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rep.len()
     }
 
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
 
-    pub fn drain(&mut self) -> IntoIter<K, T> {
-        std::mem::replace(&mut self.kv_table, BTreeMap::new()).into_iter()
+    /// The memory held by the entries, keys, values and node overhead included.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
     }
 
-    pub fn at_capacity(&self) -> bool {
-        self.kv_table.len() == self.capacity
+    pub fn write_buffer_size(&self) -> Option<usize> {
+        self.write_buffer_size
+    }
+
+    /// Whether the memtable should be flushed before `key` is written: it holds as many entries as it may (and `key`
+    /// would add another, superseded versions included), it reached its `write_buffer_size`, or the shared memory
+    /// budget ran out.
+    pub fn is_full(&self, key: &str) -> bool {
+        if self.rep.is_empty() {
+            return false;
+        }
+        return (self.rep.len() >= self.capacity && self.rep.grows_on_insert(key))
+            || self.write_buffer_size.is_some_and(|size| self.bytes() >= size)
+            || self.write_buffer_manager.as_ref().is_some_and(WriteBufferManager::over_budget);
+    }
+
+    /// Whether `entries` more entries can go in without the memtable needing a flush first.
+    pub fn has_room_for(&self, entries: usize) -> bool {
+        return self.rep.len() + entries <= self.capacity
+            && self.write_buffer_size.map_or(true, |size| self.bytes() < size)
+            && !self.write_buffer_manager.as_ref().is_some_and(WriteBufferManager::over_budget);
+    }
+}
+
+impl Drop for Memtable {
+    fn drop(&mut self) {
+        if let Some(manager) = self.write_buffer_manager.as_ref() {
+            manager.free(self.bytes());
+        }
     }
 }
 
@@ -49,14 +163,67 @@ impl<K: PartialOrd + Hash + Ord, T> Memtable<K, T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memtable_rep::VectorRep;
 
     #[test]
     fn it_works() {
         let mut memtable = Memtable::new(5);
-        memtable.insert("k1", "v1");
-        assert_eq!(memtable.get("k1"), Some(&"v1"));
+        memtable.insert("k1".to_owned(), "v1".to_owned());
+        assert_eq!(memtable.get("k1"), Some("v1".to_owned()));
     }
-}
 
+    #[test]
+    fn test_remove_range() {
+        let mut memtable = Memtable::new(5);
+        for k in ["k1", "k2", "k3", "k4"] {
+            memtable.insert(k.to_owned(), k.to_owned());
+        }
+        memtable.remove_range("k2", "k4");
+        let remaining: Vec<_> = memtable.range("k0", "k9").map(|(k, _)| k).collect();
+        assert_eq!(remaining, vec!["k1", "k4"]);
+    }
 
+    #[test]
+    fn test_byte_accounting() {
+        let manager = WriteBufferManager::new(1000);
+        let mut memtable = Memtable::new(100).with_write_buffer_size(Some(200)).with_write_buffer_manager(Some(manager.clone()));
+        memtable.insert("k1".to_owned(), "v".repeat(10));
+        let one = memtable.bytes();
+        assert!(one >= 2 + 10 + 2 * std::mem::size_of::<String>());
+        memtable.insert("k1".to_owned(), "v".repeat(150));
+        assert_eq!(memtable.bytes(), one + 140);
+        assert_eq!(manager.memory_usage(), memtable.bytes());
+        assert!(memtable.is_full("k2"));
+
+        memtable.remove_range("k0", "k9");
+        assert_eq!((memtable.bytes(), manager.memory_usage()), (0, 0));
+        memtable.insert("k1".to_owned(), "v1".to_owned());
+        drop(memtable);
+        assert_eq!(manager.memory_usage(), 0);
+    }
 
+    #[test]
+    fn test_superseded_versions_are_charged() {
+        let mut memtable = Memtable::new(100).with_rep(Box::new(VectorRep::default()));
+        memtable.insert("k2".to_owned(), "v".repeat(10));
+        memtable.insert("k1".to_owned(), "v".repeat(10));
+        let two = memtable.bytes();
+        //an out of order write leaves the old version in the vector, taking up memory until the flush
+        memtable.insert("k2".to_owned(), "v".repeat(10));
+        assert_eq!(memtable.bytes(), two + two / 2);
+        assert_eq!(memtable.drain().count(), 2);
+        assert_eq!(memtable.bytes(), 0);
+    }
+
+    #[test]
+    fn test_superseded_versions_fill_capacity() {
+        let mut memtable = Memtable::new(3).with_rep(Box::new(VectorRep::default()));
+        memtable.insert("k2".to_owned(), "v1".to_owned());
+        memtable.insert("k1".to_owned(), "v1".to_owned());
+        assert!(!memtable.is_full("k2"));
+        memtable.insert("k2".to_owned(), "v2".to_owned());
+        //both keys are already there, but another out of order write would still grow the vector
+        assert!(memtable.is_full("k1"));
+        assert!(!memtable.is_full("k2"));
+    }
+}
@@ -0,0 +1,343 @@
+use crossbeam_skiplist::SkipMap;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::ops::Bound::{Excluded, Included};
+
+type Entry = (String, String);
+
+// inline size of a key-value pair
+const SLOT: usize = 2 * size_of::<String>();
+
+/// The data structure holding the memtable's entries, selected with
+/// [`LSMBuilder::memtable_rep`](crate::LSMBuilder::memtable_rep).
+///
+/// A rep only stores entries; capacity and memory accounting are left to the engine.
+pub trait MemtableRep: Send + Sync {
+    /// Inserts `key`, returning the entry it replaced, if the rep replaces entries in place.
+    fn insert(&mut self, key: String, value: String) -> Option<Entry>;
+
+    /// The newest value written for `key`.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// The newest entry of every key in `start..end`, in key order.
+    fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = Entry> + 'a>;
+
+    /// Removes every key in `start..end`, returning all the entries removed.
+    fn remove_range(&mut self, start: &str, end: &str) -> Vec<Entry>;
+
+    /// Empties the rep, handing back the newest entry of every key in key order.
+    fn drain(&mut self) -> Box<dyn Iterator<Item = Entry>>;
+
+    /// The number of entries held, superseded versions included for reps that keep them.
+    fn len(&self) -> usize;
+
+    /// Whether inserting `key` would add an entry to [`len`](MemtableRep::len) rather than replace one in place.
+    fn grows_on_insert(&self, key: &str) -> bool {
+        return self.get(key).is_none();
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// The rep as a [`ConcurrentMemtableRep`], if several writers may insert into it at once.
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentMemtableRep> {
+        return None;
+    }
+
+    /// Memory taken up by every entry beyond its key and value's heap allocations: the pair itself and its share
+    /// of the structure's nodes, pointers and slack.
+    fn entry_overhead(&self) -> usize;
+}
+
+/// A rep that several writers can insert into at once, through a shared reference.
+pub trait ConcurrentMemtableRep: MemtableRep {
+    /// Inserts `key` alongside other writers inserting different keys, returning the entry it replaced, if the rep
+    /// replaces entries in place.
+    fn insert_concurrently(&self, key: String, value: String) -> Option<Entry>;
+}
+
+/// An ordered B-tree; the default.
+#[derive(Default)]
+pub struct BTreeRep {
+    entries: BTreeMap<String, String>,
+}
+
+impl MemtableRep for BTreeRep {
+    fn insert(&mut self, key: String, value: String) -> Option<Entry> {
+        let replaced = self.entries.remove_entry(&key);
+        self.entries.insert(key, value);
+        return replaced;
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        return self.entries.get(key).cloned();
+    }
+
+    fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = Entry> + 'a> {
+        let range = self.entries.range::<str, _>((Included(start), Excluded(end)));
+        return Box::new(range.map(|(key, value)| (key.clone(), value.clone())));
+    }
+
+    fn remove_range(&mut self, start: &str, end: &str) -> Vec<Entry> {
+        let mut removed = self.entries.split_off(start);
+        let mut after = removed.split_off(end);
+        self.entries.append(&mut after);
+        return removed.into_iter().collect();
+    }
+
+    fn drain(&mut self) -> Box<dyn Iterator<Item = Entry>> {
+        return Box::new(std::mem::take(&mut self.entries).into_iter());
+    }
+
+    fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    fn entry_overhead(&self) -> usize {
+        //b-tree nodes are about two thirds full on average, so every slot comes with half a slot of slack,
+        //plus a share of the node's parent pointer and lengths
+        return SLOT + SLOT / 2 + 2;
+    }
+}
+
+/// A concurrent skiplist: writers of different keys can insert at the same time through a
+/// [`ConcurrentEngine`](crate::ConcurrentEngine).
+#[derive(Default)]
+pub struct SkipListRep {
+    entries: SkipMap<String, String>,
+}
+
+impl MemtableRep for SkipListRep {
+    fn insert(&mut self, key: String, value: String) -> Option<Entry> {
+        let replaced = self.entries.remove(&key).map(|entry| (entry.key().clone(), entry.value().clone()));
+        self.entries.insert(key, value);
+        return replaced;
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        return self.entries.get(key).map(|entry| entry.value().clone());
+    }
+
+    fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = Entry> + 'a> {
+        let range = self.entries.range::<str, _>((Included(start), Excluded(end)));
+        return Box::new(range.map(|entry| (entry.key().clone(), entry.value().clone())).collect::<Vec<_>>().into_iter());
+    }
+
+    fn remove_range(&mut self, start: &str, end: &str) -> Vec<Entry> {
+        let removed = self.range(start, end).collect::<Vec<_>>();
+        for (key, _value) in removed.iter() {
+            self.entries.remove(key);
+        }
+        return removed;
+    }
+
+    fn drain(&mut self) -> Box<dyn Iterator<Item = Entry>> {
+        return Box::new(std::mem::take(&mut self.entries).into_iter());
+    }
+
+    fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentMemtableRep> {
+        return Some(self);
+    }
+
+    fn entry_overhead(&self) -> usize {
+        //every node carries its height and reference count, and its tower averages a little over one pointer
+        return SLOT + 3 * size_of::<usize>();
+    }
+}
+
+impl ConcurrentMemtableRep for SkipListRep {
+    fn insert_concurrently(&self, key: String, value: String) -> Option<Entry> {
+        //nobody else writes this key meanwhile, so what was there before is what gets replaced
+        let replaced = self.entries.get(&key).map(|entry| (entry.key().clone(), entry.value().clone()));
+        self.entries.insert(key, value);
+        return replaced;
+    }
+}
+
+/// An append-only vector, for bulk loads of already sorted keys.
+///
+/// Writes in ascending key order keep it sorted, and point lookups binary search it. Writes out of order are
+/// appended all the same, leaving superseded versions in place until the flush; lookups then fall back to a scan. The
+/// superseded versions count towards the memtable's capacity like any other entry.
+#[derive(Default)]
+pub struct VectorRep {
+    entries: Vec<Entry>,
+    sorted: bool,
+}
+
+impl VectorRep {
+    /// Every key's newest entry, in key order.
+    fn newest(&self) -> Vec<&Entry> {
+        let mut newest = BTreeMap::new();
+        for entry in self.entries.iter() {
+            newest.insert(entry.0.as_str(), entry);
+        }
+        return newest.into_values().collect();
+    }
+}
+
+impl MemtableRep for VectorRep {
+    fn insert(&mut self, key: String, value: String) -> Option<Entry> {
+        if self.entries.is_empty() {
+            self.sorted = true;
+        }
+        match self.entries.last_mut() {
+            Some(last) if last.0 == key => return Some(std::mem::replace(last, (key, value))),
+            Some(last) if last.0 > key => self.sorted = false,
+            _ => {}
+        }
+        self.entries.push((key, value));
+        return None;
+    }
+
+    fn grows_on_insert(&self, key: &str) -> bool {
+        //only a rewrite of the last key is made in place
+        return self.entries.last().map_or(true, |last| last.0 != key);
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        if self.sorted {
+            let index = self.entries.binary_search_by(|entry| entry.0.as_str().cmp(key)).ok()?;
+            return Some(self.entries[index].1.clone());
+        }
+        return self.entries.iter().rev().find(|entry| entry.0 == key).map(|entry| entry.1.clone());
+    }
+
+    fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = Entry> + 'a> {
+        let (start, end) = (start.to_owned(), end.to_owned());
+        if self.sorted {
+            let from = self.entries.partition_point(|entry| entry.0 < start);
+            let range = self.entries[from..].iter().take_while(move |entry| entry.0 < end);
+            return Box::new(range.cloned());
+        }
+        let range = self.newest().into_iter().filter(|entry| entry.0 >= start && entry.0 < end);
+        return Box::new(range.cloned().collect::<Vec<_>>().into_iter());
+    }
+
+    fn remove_range(&mut self, start: &str, end: &str) -> Vec<Entry> {
+        let (removed, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| entry.0.as_str() >= start && entry.0.as_str() < end);
+        self.entries = kept;
+        return removed;
+    }
+
+    fn drain(&mut self) -> Box<dyn Iterator<Item = Entry>> {
+        let mut entries = std::mem::take(&mut self.entries);
+        if !self.sorted {
+            //the sort is stable, so among equal keys the newest comes last
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries.reverse();
+            entries.dedup_by(|newer, older| newer.0 == older.0);
+            entries.reverse();
+        }
+        return Box::new(entries.into_iter());
+    }
+
+    fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    fn entry_overhead(&self) -> usize {
+        //the vector doubles as it grows, so on average it has half a slot of spare capacity per entry
+        return SLOT + SLOT / 2;
+    }
+}
+
+/// A hash table, for workloads dominated by point lookups. Scans and flushes have to sort the keys first.
+#[derive(Default)]
+pub struct HashRep {
+    entries: HashMap<String, String>,
+}
+
+impl MemtableRep for HashRep {
+    fn insert(&mut self, key: String, value: String) -> Option<Entry> {
+        let replaced = self.entries.remove_entry(&key);
+        self.entries.insert(key, value);
+        return replaced;
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        return self.entries.get(key).cloned();
+    }
+
+    fn range<'a>(&'a self, start: &str, end: &str) -> Box<dyn Iterator<Item = Entry> + 'a> {
+        let mut range = self
+            .entries
+            .iter()
+            .filter(|(key, _value)| key.as_str() >= start && key.as_str() < end)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        range.sort_unstable();
+        return Box::new(range.into_iter());
+    }
+
+    fn remove_range(&mut self, start: &str, end: &str) -> Vec<Entry> {
+        let keys = self.range(start, end).map(|(key, _value)| key).collect::<Vec<_>>();
+        return keys.iter().filter_map(|key| self.entries.remove_entry(key)).collect();
+    }
+
+    fn drain(&mut self) -> Box<dyn Iterator<Item = Entry>> {
+        let mut entries = self.entries.drain().collect::<Vec<_>>();
+        entries.sort_unstable();
+        return Box::new(entries.into_iter());
+    }
+
+    fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    fn entry_overhead(&self) -> usize {
+        //the table is kept at most 7/8 full, with a control byte per slot
+        return SLOT + SLOT / 7 + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(mut rep: Box<dyn MemtableRep>) {
+        for (key, value) in [("k1", "v1"), ("k3", "v3"), ("k2", "v2"), ("k3", "v3'"), ("k4", "v4")] {
+            rep.insert(key.to_owned(), value.to_owned());
+        }
+        assert_eq!(rep.get("k3"), Some("v3'".to_owned()));
+        assert_eq!(rep.get("k0"), None);
+        let range = rep.range("k2", "k4").collect::<Vec<_>>();
+        assert_eq!(range, vec![("k2".to_owned(), "v2".to_owned()), ("k3".to_owned(), "v3'".to_owned())]);
+
+        let removed = rep.remove_range("k4", "k9");
+        assert_eq!(removed, vec![("k4".to_owned(), "v4".to_owned())]);
+        assert_eq!(rep.get("k4"), None);
+
+        let drained = rep.drain().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>();
+        assert_eq!(drained, vec!["k1=v1", "k2=v2", "k3=v3'"]);
+        assert!(rep.is_empty());
+    }
+
+    #[test]
+    fn test_reps() {
+        exercise(Box::new(BTreeRep::default()));
+        exercise(Box::new(SkipListRep::default()));
+        exercise(Box::new(VectorRep::default()));
+        exercise(Box::new(HashRep::default()));
+    }
+
+    #[test]
+    fn test_vector_rep_sorted_load() {
+        let mut rep = VectorRep::default();
+        for i in 0..100 {
+            rep.insert(format!("k{:03}", i), i.to_string());
+        }
+        assert!(rep.sorted);
+        assert_eq!(rep.insert("k099".to_owned(), "new".to_owned()).map(|entry| entry.1), Some("99".to_owned()));
+        assert_eq!(rep.get("k050"), Some("50".to_owned()));
+        assert_eq!(rep.range("k010", "k013").count(), 3);
+        assert_eq!(rep.len(), 100);
+    }
+}
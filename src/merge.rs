@@ -0,0 +1,43 @@
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+lazy_static! {
+    // prefix marking a stored value as a list of pending merge operands rather than a plain value
+    static ref OPERANDS_MARKER: String = {
+        let rng: StdRng = SeedableRng::seed_from_u64(21);
+        rng.sample_iter(&Alphanumeric).take(20).collect::<String>()
+    };
+}
+
+/// A user supplied read-modify-write operation.
+///
+/// Operands handed to [`LSMEngine::merge`](crate::LSMEngine::merge) are recorded as-is and only combined
+/// with the existing value when the key is read or when segments are compacted.
+pub trait MergeOperator: Send + Sync {
+    /// Combines `operands` (oldest first) with the `existing` value of `key`, if any.
+    fn full_merge(&self, key: &str, existing: Option<&str>, operands: &[String]) -> String;
+}
+
+pub(crate) fn encode_operands(operands: &[String]) -> String {
+    let encoded = serde_json::to_string(operands).expect("a list of strings is always serializable");
+    return format!("{}{}", *OPERANDS_MARKER, encoded);
+}
+
+pub(crate) fn decode_operands(value: &str) -> Option<Vec<String>> {
+    let encoded = value.strip_prefix(OPERANDS_MARKER.as_str())?;
+    return serde_json::from_str(encoded).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operands_round_trip() {
+        let operands = vec!["1".to_owned(), "2".to_owned()];
+        let encoded = encode_operands(&operands);
+        assert_eq!(decode_operands(&encoded), Some(operands));
+        assert_eq!(decode_operands("plain value"), None);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{Error, Result};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// What a [`Listener`] serves: the protocol spoken on each connection, and what every connection shares.
+pub trait Protocol: 'static {
+    //such as the engine
+    type Shared: Clone + Send + 'static;
+
+    //serves one connection until the client hangs up
+    fn serve(shared: &Self::Shared, stream: TcpStream) -> Result<()>;
+}
+
+/// Accepts connections until `stopped` is set, serving each on a thread of its own.
+fn accept<P: Protocol>(listener: TcpListener, shared: P::Shared, stopped: &AtomicBool) -> Result<()> {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let shared = shared.clone();
+        let stream = stream?;
+        //a connection that breaks only takes itself down
+        thread::spawn(move || P::serve(&shared, stream));
+    }
+    return Ok(());
+}
+
+/// A socket bound for one of the servers, which serves every connection on a thread of its own.
+pub struct Listener<P: Protocol> {
+    listener: TcpListener,
+    shared: P::Shared,
+}
+
+impl<P: Protocol> Listener<P> {
+    pub(crate) fn bind_to<A: ToSocketAddrs>(addr: A, shared: P::Shared) -> Result<Self> {
+        return Ok(Listener { listener: TcpListener::bind(addr)?, shared });
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        return Ok(self.listener.local_addr()?);
+    }
+
+    /// Accepts connections until the process exits.
+    pub fn run(self) -> Result<()> {
+        return accept::<P>(self.listener, self.shared, &AtomicBool::new(false));
+    }
+
+    /// Accepts connections on a thread of its own until the returned handle shuts the server down.
+    pub fn spawn(self) -> Result<ServerHandle> {
+        let addr = self.listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let Listener { listener, shared } = self;
+        let thread = thread::spawn(move || accept::<P>(listener, shared, &stop));
+        return Ok(ServerHandle { addr, stopped, thread: Some(thread) });
+    }
+}
+
+/// A server running in the background until it is shut down or dropped.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    /// Stops accepting connections. The connections already open are served until their clients hang up.
+    pub fn shutdown(mut self) -> Result<()> {
+        return self.stop();
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        self.stopped.store(true, Ordering::SeqCst);
+        //wakes up the accepting thread, which then sees it was stopped
+        let _ = TcpStream::connect(self.addr);
+        return thread.join().unwrap_or_else(|_| Err(Error::ProtocolError { reason: "the server thread panicked".to_owned() }));
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
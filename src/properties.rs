@@ -0,0 +1,101 @@
+use std::fmt::Write;
+
+/// The shape of a single segment file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentDescription {
+    pub index: usize,
+    pub bytes: u64,
+    pub entries: usize,
+    pub tombstones: usize,
+    pub first_key: Option<String>,
+    pub last_key: Option<String>,
+}
+
+/// The segments of one level; see [`Description`] for what each level holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelDescription {
+    pub level: usize,
+    pub segments: Vec<SegmentDescription>,
+}
+
+impl LevelDescription {
+    pub fn bytes(&self) -> u64 {
+        return self.segments.iter().map(|segment| segment.bytes).sum();
+    }
+
+    pub fn entries(&self) -> usize {
+        return self.segments.iter().map(|segment| segment.entries).sum();
+    }
+
+    pub fn tombstones(&self) -> usize {
+        return self.segments.iter().map(|segment| segment.tombstones).sum();
+    }
+}
+
+/// The shape of the whole engine, as returned by [`LSMEngine::describe`](crate::LSMEngine::describe).
+///
+/// Level 0 holds the flushed segments still waiting to be merged, whose key ranges may overlap; level 1 is the
+/// sorted run they are merged into. Empty levels are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    pub memtable_entries: usize,
+    pub memtable_capacity: usize,
+    /// Memory held by the memtable's entries, node overhead included.
+    pub memtable_bytes: usize,
+    pub write_buffer_size: Option<usize>,
+    pub sparse_index_entries: usize,
+    pub range_tombstones: usize,
+    pub levels: Vec<LevelDescription>,
+}
+
+impl Description {
+    fn segments(&self) -> impl Iterator<Item = &SegmentDescription> {
+        return self.levels.iter().flat_map(|level| level.segments.iter());
+    }
+
+    fn unmerged(&self) -> Option<&LevelDescription> {
+        return self.levels.iter().find(|level| level.level == 0);
+    }
+
+    /// Looks up a single property by name; see [`LSMEngine::get_property`](crate::LSMEngine::get_property).
+    pub fn property(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "lsm.num-levels" => self.levels.len().to_string(),
+            "lsm.num-segments" => self.segments().count().to_string(),
+            "lsm.total-segment-bytes" => self.levels.iter().map(LevelDescription::bytes).sum::<u64>().to_string(),
+            "lsm.num-entries-segments" => self.levels.iter().map(LevelDescription::entries).sum::<usize>().to_string(),
+            "lsm.num-tombstones" => self.levels.iter().map(LevelDescription::tombstones).sum::<usize>().to_string(),
+            "lsm.num-range-tombstones" => self.range_tombstones.to_string(),
+            "lsm.num-entries-memtable" => self.memtable_entries.to_string(),
+            "lsm.memtable-capacity" => self.memtable_capacity.to_string(),
+            "lsm.memtable-bytes" => self.memtable_bytes.to_string(),
+            "lsm.write-buffer-size" => self.write_buffer_size.map_or("none".to_owned(), |size| size.to_string()),
+            "lsm.sparse-index-entries" => self.sparse_index_entries.to_string(),
+            "lsm.num-unmerged-segments" => self.unmerged().map_or(0, |level| level.segments.len()).to_string(),
+            "lsm.pending-compaction-bytes" => self.unmerged().map_or(0, LevelDescription::bytes).to_string(),
+            "lsm.levels" => self.levels_table(),
+            _ => return None,
+        };
+        return Some(value);
+    }
+
+    fn levels_table(&self) -> String {
+        let mut out = String::from("level segment entries tombstones bytes first_key last_key\n");
+        for level in self.levels.iter() {
+            for segment in level.segments.iter() {
+                let _ = writeln!(
+                    out,
+                    "{} {} {} {} {} {} {}",
+                    level.level,
+                    segment.index,
+                    segment.entries,
+                    segment.tombstones,
+                    segment.bytes,
+                    segment.first_key.as_deref().unwrap_or("-"),
+                    segment.last_key.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        return out;
+    }
+}
@@ -0,0 +1,43 @@
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Instant;
+
+lazy_static! {
+    // prefix marking a WAL record as a range deletion; the record's key is the start of the range
+    static ref RANGE_MARKER: String = {
+        let rng: StdRng = SeedableRng::seed_from_u64(22);
+        rng.sample_iter(&Alphanumeric).take(20).collect::<String>()
+    };
+}
+
+/// Deletes every key in `start..end` that was written before the tombstone was.
+#[derive(Debug, Clone)]
+pub struct RangeTombstone {
+    pub start: String,
+    pub end: String,
+    pub created_at: Instant,
+}
+
+impl RangeTombstone {
+    pub fn new(start: &str, end: &str) -> Self {
+        return RangeTombstone {
+            start: start.to_owned(),
+            end: end.to_owned(),
+            created_at: Instant::now(),
+        };
+    }
+
+    /// Whether `key`, written no later than `written_at`, is deleted by this tombstone.
+    pub fn covers(&self, key: &str, written_at: Instant) -> bool {
+        return written_at < self.created_at && self.start.as_str() <= key && key < self.end.as_str();
+    }
+}
+
+pub(crate) fn encode_end(end: &str) -> String {
+    return format!("{}{}", *RANGE_MARKER, end);
+}
+
+pub(crate) fn decode_end(value: &str) -> Option<&str> {
+    return value.strip_prefix(RANGE_MARKER.as_str());
+}
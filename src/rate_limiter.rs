@@ -0,0 +1,115 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    bytes_per_second: u64,
+    // may go negative: a request larger than what is available borrows from the future and sleeps off the debt
+    available: f64,
+    refilled_at: Instant,
+    total_bytes: u64,
+    total_wait: Duration,
+}
+
+impl Bucket {
+    fn burst(&self) -> f64 {
+        //at most a tenth of a second's worth of writes goes out in one burst
+        return (self.bytes_per_second as f64 / 10.0).max(1.0);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.available = (self.available + elapsed * self.bytes_per_second as f64).min(self.burst());
+        self.refilled_at = now;
+    }
+}
+
+/// A token bucket capping the write bandwidth of flushes and compactions, registered with
+/// [`LSMBuilder::rate_limiter`](crate::LSMBuilder::rate_limiter).
+///
+/// Clones share the same bucket, so one limiter can cap the combined background I/O of several engines. It paces the
+/// disk, not the engine: a flush or compaction holds on to its engine while it sleeps.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let mut bucket = Bucket {
+            bytes_per_second: bytes_per_second.max(1),
+            available: 0.0,
+            refilled_at: Instant::now(),
+            total_bytes: 0,
+            total_wait: Duration::default(),
+        };
+        bucket.available = bucket.burst();
+        return RateLimiter { bucket: Arc::new(Mutex::new(bucket)) };
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        return self.bucket.lock().unwrap_or_else(PoisonError::into_inner).bytes_per_second;
+    }
+
+    /// Changes the rate on the fly, e.g. to let compactions run faster off-peak.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        bucket.refill(Instant::now());
+        bucket.bytes_per_second = bytes_per_second.max(1);
+    }
+
+    /// Takes `bytes` out of the bucket, sleeping until the rate allows them through. Returns how long it slept.
+    pub fn request(&self, bytes: u64) -> Duration {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            bucket.refill(Instant::now());
+            bucket.available -= bytes as f64;
+            bucket.total_bytes += bytes;
+            let wait = if bucket.available < 0.0 {
+                Duration::from_secs_f64(-bucket.available / bucket.bytes_per_second as f64)
+            } else {
+                Duration::default()
+            };
+            bucket.total_wait += wait;
+            wait
+        };
+        if wait > Duration::default() {
+            thread::sleep(wait);
+        }
+        return wait;
+    }
+
+    /// Bytes requested through this limiter so far.
+    pub fn total_bytes(&self) -> u64 {
+        return self.bucket.lock().unwrap_or_else(PoisonError::into_inner).total_bytes;
+    }
+
+    /// Time spent sleeping in [`request`](RateLimiter::request) so far.
+    pub fn total_wait(&self) -> Duration {
+        return self.bucket.lock().unwrap_or_else(PoisonError::into_inner).total_wait;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(10_000);
+        //the bucket starts with a full burst of 1000 bytes
+        assert_eq!(limiter.request(1000), Duration::default());
+        let started = Instant::now();
+        let wait = limiter.request(1000);
+        assert!(wait >= Duration::from_millis(50));
+        assert!(started.elapsed() >= wait);
+
+        let shared = limiter.clone();
+        shared.request(10);
+        assert_eq!(limiter.total_bytes(), 2010);
+        assert!(limiter.total_wait() >= wait);
+
+        limiter.set_bytes_per_second(20_000);
+        assert_eq!(shared.bytes_per_second(), 20_000);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::kv::KVPair;
+use crate::merge;
+use crate::range_tombstone;
+use crate::batch::WriteBatch;
+use crate::sst_writer;
+use crate::{Error, Result, TOMBSTONE_VALUE};
+
+/// A write as logged in the WAL, or as stored in a segment, which only ever holds puts, deletes and merges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Put { key: String, value: String },
+    Delete { key: String },
+    Merge { key: String, operands: Vec<String> },
+    DeleteRange { start: String, end: String },
+    /// Puts and deletes applied atomically.
+    Batch(Vec<Record>),
+    /// Files ingested into a data directory, which went into the manifest rather than the WAL: this only says when.
+    Ingest { files: Vec<String> },
+}
+
+/// Whether `value` carries one of the markers the engine encodes merges, range deletes, batches and ingests with,
+/// rather than being a plain value or a tombstone.
+pub(crate) fn is_marked(value: &str) -> bool {
+    return WriteBatch::decode(value).is_some()
+        || range_tombstone::decode_end(value).is_some()
+        || merge::decode_operands(value).is_some()
+        || sst_writer::decode_ingest(value).is_some();
+}
+
+/// Refuses a value written for `key` that the WAL would take for one of the engine's own records on replay.
+pub(crate) fn check_value(key: &str, value: &str) -> Result<()> {
+    if is_marked(value) {
+        return Err(Error::ReservedValue { key: key.to_owned() });
+    }
+    return Ok(());
+}
+
+impl Record {
+    pub(crate) fn decode(kv: KVPair) -> Record {
+        if let Some(batch) = WriteBatch::decode(&kv.value) {
+            let records = batch.entries.into_iter().map(|kv| match kv.value == *TOMBSTONE_VALUE {
+                true => Record::Delete { key: kv.key },
+                false => Record::Put { key: kv.key, value: kv.value },
+            });
+            return Record::Batch(records.collect());
+        }
+        if let Some(files) = sst_writer::decode_ingest(&kv.value) {
+            return Record::Ingest { files };
+        }
+        if let Some(end) = range_tombstone::decode_end(&kv.value) {
+            return Record::DeleteRange { start: kv.key, end: end.to_owned() };
+        }
+        if let Some(operands) = merge::decode_operands(&kv.value) {
+            return Record::Merge { key: kv.key, operands };
+        }
+        if kv.value == *TOMBSTONE_VALUE {
+            return Record::Delete { key: kv.key };
+        }
+        return Record::Put { key: kv.key, value: kv.value };
+    }
+
+    /// The key written, or the start of the range deleted. Batches and ingests have none.
+    pub fn key(&self) -> Option<&str> {
+        return match self {
+            Record::Put { key, .. } | Record::Delete { key } | Record::Merge { key, .. } => Some(key),
+            Record::DeleteRange { start, .. } => Some(start),
+            Record::Batch(_) | Record::Ingest { .. } => None,
+        };
+    }
+}
@@ -0,0 +1,145 @@
+use crate::checksum::Checksum;
+use crate::kv::{KVPair, LineReader};
+use crate::manifest::{self, Manifest, SegmentEntry, Storage};
+use crate::verify::Problem;
+use crate::wal::Wal;
+use crate::Result;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+/// What [`LSMEngine::repair`](crate::LSMEngine::repair) salvaged, and what it had to give up on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Whether the manifest was missing or unreadable. Every segment file found then goes into level 0, newest file
+    /// last, and the range tombstones no merge had applied yet are lost.
+    pub manifest_lost: bool,
+    pub segments: usize,
+    /// The entries kept across every segment.
+    pub entries: usize,
+    /// The records kept from the WAL.
+    pub records: usize,
+    /// The sequence number the records kept were renumbered from, past every one the WAL had issued, so that
+    /// followers and subscribers still holding an older one are told it's unavailable and start over rather than
+    /// skip records. Without a manifest, what was issued is unknown and numbering starts again from 1.
+    pub first_sequence: u64,
+    /// Everything dropped: missing files, and entries or records that can't be read or are out of order. Segments
+    /// that don't match the checksum their manifest recorded are kept as far as they can be read, but listed here.
+    pub lost: Vec<Problem>,
+}
+
+//a segment or WAL file found in the data directory
+fn numbered(name: &str, extension: &str) -> Option<u64> {
+    return name.strip_suffix(extension)?.strip_suffix('.')?.parse().ok();
+}
+
+/// Reads every line of `file` it can, handing each entry to `keep`, which says whether to keep it. Returns the
+/// entries kept, reporting the rest in `lost`.
+fn salvage<F: FnMut(&KVPair) -> Option<Problem>>(dir: &Path, file: &str, lost: &mut Vec<Problem>, mut keep: F) -> Result<Vec<KVPair>> {
+    let mut kept = vec![];
+    for (offset, kv) in LineReader::new(BufReader::new(File::open(dir.join(file))?)) {
+        match kv {
+            Ok(kv) => match keep(&kv) {
+                None => kept.push(kv),
+                Some(problem) => lost.push(problem),
+            },
+            Err(error) => lost.push(Problem::Unreadable { file: file.to_owned(), offset, error: error.to_string() }),
+        }
+    }
+    return Ok(kept);
+}
+
+pub(crate) fn repair(dir: &Path) -> Result<RepairReport> {
+    let mut report = RepairReport::default();
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    let numbers = |extension: &str| {
+        let mut numbers = names.iter().filter_map(|name| numbered(name, extension)).collect::<Vec<_>>();
+        numbers.sort_unstable();
+        return numbers;
+    };
+    let (segment_files, wal_files) = (numbers("sst"), numbers("log"));
+
+    //without a manifest, file numbers are all there is to tell older segments from newer ones
+    let old = Manifest::load(dir).ok().flatten().unwrap_or_else(|| {
+        report.manifest_lost = true;
+        let level0 = segment_files.iter().enumerate().map(|(sequence, file)| SegmentEntry { file: *file, sequence, checksum: None });
+        return Manifest { level0: level0.collect(), wal: wal_files.last().copied(), ..Manifest::default() };
+    });
+    let highest = segment_files.iter().chain(wal_files.iter()).max().map_or(0, |number| number + 1);
+    let storage = Storage::new(dir, old.next_file.max(highest));
+
+    //every segment is written out again, keeping the entries that are readable and in order
+    let copy = |entry: &SegmentEntry, report: &mut RepairReport| -> Result<Option<SegmentEntry>> {
+        let file = manifest::segment_file_name(entry.file);
+        if !segment_files.contains(&entry.file) {
+            report.lost.push(Problem::Manifest(format!("{} is listed but missing", file)));
+            return Ok(None);
+        }
+        let mut previous: Option<String> = None;
+        let kept = salvage(dir, &file, &mut report.lost, |kv| {
+            if let Some(previous) = previous.as_ref().filter(|previous| **previous >= kv.key) {
+                return Some(Problem::Unsorted { file: file.clone(), key: kv.key.clone(), previous: previous.clone() });
+            }
+            previous = Some(kv.key.clone());
+            return None;
+        })?;
+        if let Some(expected) = entry.checksum {
+            let actual = Checksum::of_file(&dir.join(&file))?;
+            if actual != expected {
+                report.lost.push(Problem::ChecksumMismatch { file: file.clone(), expected, actual });
+            }
+        }
+        if kept.is_empty() {
+            return Ok(None);
+        }
+        let mut segment = storage.new_segment()?;
+        report.entries += kept.len();
+        report.segments += 1;
+        for kv in kept {
+            segment.write(kv)?;
+        }
+        segment.sync()?;
+        let number = segment.number().expect("segments in a data directory are numbered");
+        return Ok(Some(SegmentEntry { file: number, sequence: entry.sequence, checksum: Some(segment.checksum()) }));
+    };
+    let mut level0 = vec![];
+    for entry in old.level0.iter() {
+        level0.extend(copy(entry, &mut report)?);
+    }
+    let mut run = vec![];
+    for entry in old.run.iter() {
+        run.extend(copy(entry, &mut report)?);
+    }
+
+    //every line of the old WAL took a sequence number, read or not
+    report.first_sequence = old.wal_start.max(1);
+    let (wal, file) = storage.new_wal()?;
+    if let Some(number) = old.wal {
+        let name = manifest::wal_file_name(number);
+        if wal_files.contains(&number) {
+            let unreadable = report.lost.len();
+            let kept = salvage(dir, &name, &mut report.lost, |_kv| None)?;
+            report.records = kept.len();
+            report.first_sequence += (kept.len() + report.lost.len() - unreadable) as u64;
+            Wal::new(file).append(&kept, true)?;
+        } else {
+            report.lost.push(Problem::Manifest(format!("{} is listed but missing", name)));
+        }
+    }
+
+    let manifest = Manifest {
+        next_file: storage.next_file(),
+        wal: Some(wal),
+        wal_start: report.first_sequence,
+        archived_wals: vec![],
+        level0,
+        run,
+        range_tombstones: old.range_tombstones,
+    };
+    manifest.save(dir)?;
+    storage.remove_orphans(&manifest)?;
+    return Ok(report);
+}
@@ -0,0 +1,551 @@
+use crate::kv::KVPair;
+use crate::manifest::{self, Manifest, WalEntry};
+use crate::net::{Listener, Protocol};
+use crate::record::Record;
+use crate::{Error, LSMEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//how long a reader that has caught up with the WAL waits before looking for new records
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+//a WAL file being read, and the sequence number of its next record
+struct Cursor {
+    file: u64,
+    reader: BufReader<File>,
+    offset: u64,
+    sequence: u64,
+}
+
+impl Cursor {
+    fn open(dir: &Path, wal: &WalEntry) -> io::Result<Cursor> {
+        let file = File::open(dir.join(manifest::wal_file_name(wal.file)))?;
+        return Ok(Cursor { file: wal.file, reader: BufReader::new(file), offset: 0, sequence: wal.start });
+    }
+
+    /// The next line, once the engine has written all of it.
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = self.reader.read_line(&mut line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            //the engine is halfway through appending it
+            self.reader.seek(SeekFrom::Start(self.offset))?;
+            return Ok(None);
+        }
+        self.offset += read as u64;
+        return Ok(Some(line));
+    }
+}
+
+/// What an engine shares with its WAL iterators: the WALs they are reading, which it keeps past its
+/// [`wal_retention`](crate::LSMBuilder::wal_retention) until they have moved on, and how far they may read.
+#[derive(Debug, Default)]
+pub(crate) struct WalReaders {
+    //the file number each iterator is reading, by iterator
+    pinned: Mutex<HashMap<u64, u64>>,
+    next_reader: AtomicU64,
+    //the sequence number of the last record applied, past which records may yet fail to take effect
+    committed: AtomicU64,
+}
+
+impl WalReaders {
+    pub(crate) fn commit(&self, sequence: u64) {
+        self.committed.store(sequence, Ordering::SeqCst);
+    }
+
+    fn pin(&self, file: u64) -> u64 {
+        let reader = self.next_reader.fetch_add(1, Ordering::SeqCst);
+        self.pinned.lock().unwrap().insert(reader, file);
+        return reader;
+    }
+
+    fn pin_as(&self, reader: u64, file: u64) {
+        self.pinned.lock().unwrap().insert(reader, file);
+    }
+
+    fn unpin(&self, reader: u64) {
+        self.pinned.lock().unwrap().remove(&reader);
+    }
+
+    /// The oldest WAL an iterator is reading, if any is.
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        return self.pinned.lock().unwrap().values().min().copied();
+    }
+}
+
+fn load_manifest(dir: &Path) -> Result<Manifest> {
+    return Manifest::load(dir)?.ok_or(Error::NeedsDataDirectory);
+}
+
+//the error for a record whose WAL is gone, or about to be
+fn unavailable(dir: &Path, sequence: u64) -> Error {
+    let oldest = match load_manifest(dir) {
+        Ok(manifest) => manifest.wals().first().map_or(sequence, |wal| wal.start),
+        Err(error) => return error,
+    };
+    return Error::SequenceUnavailable { sequence, oldest };
+}
+
+/// The records of a data directory's WALs in the order they were logged, each with its sequence number: see
+/// [`LSMEngine::wal_iterator`].
+///
+/// It only reads as far as the engine has applied, so a record it returns has taken effect. Once it has caught up
+/// with the engine it returns `None`, and picks up where it left off when called again,
+/// following the engine from one WAL to the next. The engine keeps the WAL an iterator is reading, and every one
+/// after it, for as long as the iterator is around, whatever its [`wal_retention`](crate::LSMBuilder::wal_retention).
+pub struct WalIterator {
+    dir: PathBuf,
+    readers: Arc<WalReaders>,
+    //how the engine knows which WAL this iterator is reading
+    reader: u64,
+    current: Cursor,
+    //the WALs after the current one, oldest first
+    pending: VecDeque<WalEntry>,
+    //the sequence number of the next record to return
+    next: u64,
+}
+
+impl WalIterator {
+    pub(crate) fn open(dir: &Path, readers: &Arc<WalReaders>, since: u64) -> Result<WalIterator> {
+        let next = since + 1;
+        //every WAL is kept until it is known which one is needed
+        let reader = readers.pin(0);
+        let opened = WalIterator::open_pinned(dir, readers, reader, next);
+        if opened.is_err() {
+            readers.unpin(reader);
+        }
+        return opened;
+    }
+
+    fn open_pinned(dir: &Path, readers: &Arc<WalReaders>, reader: u64, next: u64) -> Result<WalIterator> {
+        let mut wals = VecDeque::from(load_manifest(dir)?.wals());
+        let oldest = wals.front().ok_or(Error::NeedsDataDirectory)?.start;
+        if next < oldest {
+            return Err(Error::SequenceUnavailable { sequence: next, oldest });
+        }
+        //the WALs that end before `next` are of no use
+        while wals.len() > 1 && wals[1].start <= next {
+            wals.pop_front();
+        }
+        let first = wals.pop_front().unwrap();
+        let current = match Cursor::open(dir, &first) {
+            Ok(current) => current,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Err(unavailable(dir, next)),
+            Err(error) => return Err(error.into()),
+        };
+        readers.pin_as(reader, current.file);
+        return Ok(WalIterator { dir: dir.to_path_buf(), readers: readers.clone(), reader, current, pending: wals, next });
+    }
+
+    /// Looks for WALs newer than the current one, which the engine is then done writing to.
+    fn refresh(&mut self) -> Result<bool> {
+        let current = self.current.file;
+        self.pending = load_manifest(&self.dir)?.wals().into_iter().filter(|wal| wal.file > current).collect();
+        return Ok(!self.pending.is_empty());
+    }
+
+    /// The next record as logged.
+    pub(crate) fn next_entry(&mut self) -> Option<Result<(u64, KVPair)>> {
+        loop {
+            if self.current.sequence > self.readers.committed.load(Ordering::SeqCst) {
+                return None;
+            }
+            match self.current.read_line() {
+                Ok(Some(line)) => {
+                    let sequence = self.current.sequence;
+                    self.current.sequence += 1;
+                    //a rotation copies the records of the write in progress into the new WAL
+                    if sequence < self.next {
+                        continue;
+                    }
+                    self.next = sequence + 1;
+                    return Some(serde_json::from_str(&line).map(|kv| (sequence, kv)).map_err(Error::from));
+                }
+                Ok(None) => {}
+                Err(error) => return Some(Err(error.into())),
+            }
+            //records may have been appended between reaching the end and finding a newer WAL, so it is read once more
+            if self.pending.is_empty() {
+                match self.refresh() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(error) => return Some(Err(error)),
+                }
+            }
+            let wal = self.pending.pop_front().unwrap();
+            match Cursor::open(&self.dir, &wal) {
+                Ok(cursor) => {
+                    self.readers.pin_as(self.reader, cursor.file);
+                    self.current = cursor;
+                }
+                Err(error) if error.kind() == ErrorKind::NotFound => {
+                    self.pending.push_front(wal);
+                    return Some(Err(unavailable(&self.dir, self.next)));
+                }
+                Err(error) => return Some(Err(error.into())),
+            }
+        }
+    }
+}
+
+impl Drop for WalIterator {
+    fn drop(&mut self) {
+        self.readers.unpin(self.reader);
+    }
+}
+
+impl Iterator for WalIterator {
+    type Item = Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.next_entry().map(|entry| entry.map(|(sequence, kv)| (sequence, Record::decode(kv))));
+    }
+}
+
+//a line sent from a shipper to a follower
+#[derive(Serialize, Deserialize)]
+enum Shipment {
+    Record { sequence: u64, key: String, value: String },
+    Error { message: String },
+}
+
+fn send(writer: &mut BufWriter<TcpStream>, shipment: &Shipment) -> Result<()> {
+    serde_json::to_writer(&mut *writer, shipment)?;
+    writer.write_all(b"\n")?;
+    return Ok(());
+}
+
+//streams the records after the sequence number on the first line until the follower hangs up
+fn ship(leader: &Leader, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(());
+    }
+    let opened = match line.trim().parse() {
+        Ok(since) => WalIterator::open(&leader.dir, &leader.readers, since),
+        Err(_) => Err(Error::ProtocolError { reason: format!("expected a sequence number, not {:?}", line.trim()) }),
+    };
+    let mut records = match opened {
+        Ok(records) => records,
+        Err(error) => return send(&mut writer, &Shipment::Error { message: error.to_string() }),
+    };
+    reader.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        match records.next_entry() {
+            Some(Ok((sequence, kv))) => send(&mut writer, &Shipment::Record { sequence, key: kv.key, value: kv.value })?,
+            Some(Err(error)) => return send(&mut writer, &Shipment::Error { message: error.to_string() }),
+            None => {
+                writer.flush()?;
+                //followers never send anything more, so waiting on them is how a hangup is noticed
+                match reader.read(&mut [0; 1]) {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => {}
+                    Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(error) => return Err(error.into()),
+                }
+            }
+        }
+    }
+}
+
+/// Ships the WAL of an engine in a data directory to [`Follower`]s over TCP, reading the files from outside the
+/// engine while it keeps running. A follower sends the sequence number it has applied up to, and is then sent every
+/// record after it, as the engine logs them, until it hangs up.
+pub type WalShipper = Listener<Shipping>;
+
+//what every follower's connection reads the WALs through
+#[derive(Clone)]
+pub struct Leader {
+    dir: Arc<Path>,
+    readers: Arc<WalReaders>,
+}
+
+/// The replication protocol, as a [`WalShipper`] speaks it.
+pub struct Shipping;
+
+impl Protocol for Shipping {
+    type Shared = Leader;
+
+    fn serve(leader: &Leader, stream: TcpStream) -> Result<()> {
+        return ship(leader, stream);
+    }
+}
+
+impl WalShipper {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: &LSMEngine) -> Result<Self> {
+        let dir = Arc::from(engine.storage.as_ref().ok_or(Error::NeedsDataDirectory)?.dir());
+        return Listener::bind_to(addr, Leader { dir, readers: engine.wal_readers.clone() });
+    }
+}
+
+/// The records a [`WalShipper`] sends, each with its sequence number. It blocks until the next one arrives, and ends
+/// when the shipper hangs up.
+pub struct ReplicationStream {
+    reader: BufReader<TcpStream>,
+}
+
+impl ReplicationStream {
+    /// Asks the shipper at `addr` for every record after `since`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, since: u64) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        writeln!(stream, "{}", since)?;
+        return Ok(ReplicationStream { reader: BufReader::new(stream) });
+    }
+}
+
+impl Iterator for ReplicationStream {
+    type Item = Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(_) if !line.ends_with('\n') => {
+                return Some(Err(Error::ProtocolError { reason: "the shipper hung up halfway through a record".to_owned() }))
+            }
+            Ok(_) => {}
+            Err(error) => return Some(Err(error.into())),
+        }
+        return Some(match serde_json::from_str(&line) {
+            Ok(Shipment::Record { sequence, key, value }) => Ok((sequence, Record::decode(KVPair { key, value }))),
+            Ok(Shipment::Error { message }) => Err(Error::ServerError { message }),
+            Err(error) => Err(error.into()),
+        });
+    }
+}
+
+/// A read-only copy of another engine, kept up to date by applying its WAL records in order: a warm standby.
+///
+/// The follower's engine needs the same merge operator as the leader's to apply merges. A leader in a data directory
+/// logs each ingest as a [`Record::Ingest`] naming the files, not their entries, so [`apply`](Follower::apply) rejects
+/// it with [`Error::IngestNotReplicated`], and the follower has to be seeded again from a checkpoint taken after it.
+pub struct Follower {
+    engine: LSMEngine,
+    applied: u64,
+}
+
+impl Follower {
+    /// Follows on from sequence number `applied`: 0 for an empty engine, or the
+    /// [`latest_sequence`](LSMEngine::latest_sequence) of a checkpoint of the leader that `engine` was opened from.
+    pub fn new(engine: LSMEngine, applied: u64) -> Self {
+        return Follower { engine, applied };
+    }
+
+    /// The sequence number of the last record applied.
+    pub fn applied(&self) -> u64 {
+        return self.applied;
+    }
+
+    /// Applies the record with sequence number `sequence`, unless it was applied already. Skipping one is an error, and
+    /// so is an ingest.
+    pub fn apply(&mut self, sequence: u64, record: Record) -> Result<()> {
+        if sequence <= self.applied {
+            return Ok(());
+        }
+        if sequence != self.applied + 1 {
+            return Err(Error::ReplicationGap { expected: self.applied + 1, found: sequence });
+        }
+        if let Record::Ingest { .. } = record {
+            return Err(Error::IngestNotReplicated { sequence });
+        }
+        self.engine.apply_record(record)?;
+        self.applied = sequence;
+        return Ok(());
+    }
+
+    /// Applies `records` until the one with sequence number `through` is in, or they run out.
+    pub fn catch_up<I: Iterator<Item = Result<(u64, Record)>>>(&mut self, records: &mut I, through: u64) -> Result<()> {
+        while self.applied < through {
+            let (sequence, record) = match records.next() {
+                Some(entry) => entry?,
+                None => break,
+            };
+            self.apply(sequence, record)?;
+        }
+        return Ok(());
+    }
+
+    /// Applies what the [`WalShipper`] at `addr` sends until it hangs up.
+    pub fn follow<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let mut stream = ReplicationStream::connect(addr, self.applied)?;
+        return self.catch_up(&mut stream, u64::MAX);
+    }
+
+    pub fn read(&mut self, key: &str) -> Result<Option<String>> {
+        return self.engine.read(key);
+    }
+
+    pub fn multi_get(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        return self.engine.multi_get(keys);
+    }
+
+    pub fn contains(&mut self, key: &str) -> Result<bool> {
+        return self.engine.contains(key);
+    }
+
+    pub fn scan(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        return self.engine.scan(start, end);
+    }
+
+    /// Stops following, handing back the engine, say to take over from the leader.
+    pub fn into_inner(self) -> LSMEngine {
+        return self.engine;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LSMBuilder, WriteBatch};
+    use tempfile::tempdir;
+
+    fn builder() -> LSMBuilder {
+        return LSMBuilder::new().segment_size(6).inmemory_capacity(3).wal_retention(100);
+    }
+
+    fn write(leader: &mut LSMEngine, from: usize, to: usize) -> Result<()> {
+        for i in from..to {
+            leader.write(format!("k{:03}", i), format!("v{}", i))?;
+            if i % 7 == 6 {
+                leader.delete(&format!("k{:03}", i - 1))?;
+            }
+            if i % 10 == 9 {
+                let mut batch = WriteBatch::new();
+                batch.put(format!("b{:03}", i), "batched".to_owned());
+                batch.delete(&format!("k{:03}", i - 2));
+                leader.write_batch(batch)?;
+            }
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn test_wal_iterator() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut leader = builder().open(dir.path())?;
+        leader.write("k1".to_owned(), "v1".to_owned())?;
+        leader.delete("k1")?;
+        let mut batch = WriteBatch::new();
+        batch.put("k2".to_owned(), "v2".to_owned());
+        batch.put("k3".to_owned(), "v3".to_owned());
+        leader.write_batch(batch)?;
+        assert_eq!(leader.latest_sequence(), 3);
+
+        let mut records = leader.wal_iterator(0)?;
+        assert_eq!(records.next().transpose()?, Some((1, Record::Put { key: "k1".to_owned(), value: "v1".to_owned() })));
+        assert_eq!(records.next().transpose()?, Some((2, Record::Delete { key: "k1".to_owned() })));
+        assert!(matches!(records.next().transpose()?, Some((3, Record::Batch(_)))));
+        assert!(records.next().is_none());
+
+        //it follows the engine through flushes into newer WALs
+        write(&mut leader, 0, 30)?;
+        let sequences = records.map(|entry| entry.map(|(sequence, _record)| sequence)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(sequences, (4..=leader.latest_sequence()).collect::<Vec<_>>());
+        assert_eq!(leader.wal_iterator(20)?.next().transpose()?.map(|(sequence, _record)| sequence), Some(21));
+
+        //sequence numbers carry on where they left off after a restart
+        let latest = leader.latest_sequence();
+        drop(leader);
+        let mut leader = builder().open(dir.path())?;
+        assert_eq!(leader.latest_sequence(), latest);
+        leader.write("k4".to_owned(), "v4".to_owned())?;
+        assert_eq!(leader.latest_sequence(), latest + 1);
+        assert_eq!(leader.wal_iterator(latest)?.next().transpose()?.map(|(sequence, _record)| sequence), Some(latest + 1));
+        assert!(crate::verify_dir(dir.path())?.is_empty());
+
+        //WALs past the retention are deleted
+        drop(leader);
+        let mut leader = builder().wal_retention(1).open(dir.path())?;
+        write(&mut leader, 30, 60)?;
+        assert!(matches!(leader.wal_iterator(0), Err(Error::SequenceUnavailable { sequence: 1, .. })));
+        assert!(matches!(LSMBuilder::new().build().wal_iterator(0), Err(Error::NeedsDataDirectory)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_iterators_keep_their_wals() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut leader = builder().wal_retention(0).open(dir.path())?;
+        let mut records = leader.wal_iterator(0)?;
+        write(&mut leader, 0, 5)?;
+        assert_eq!(records.next().transpose()?.map(|(sequence, _record)| sequence), Some(1));
+
+        //without any retention, the WALs the iterator has yet to read outlive the engine's flushes
+        write(&mut leader, 5, 60)?;
+        let sequences = records.by_ref().map(|entry| entry.map(|(sequence, _record)| sequence)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(sequences, (2..=leader.latest_sequence()).collect::<Vec<_>>());
+        assert!(load_manifest(dir.path())?.wals().len() > 1);
+
+        //and once it's gone, so are they
+        drop(records);
+        write(&mut leader, 60, 70)?;
+        assert_eq!(load_manifest(dir.path())?.wals().len(), 1);
+        assert!(matches!(leader.wal_iterator(0), Err(Error::SequenceUnavailable { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_follower_converges() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut leader = builder().open(dir.path())?;
+        write(&mut leader, 0, 20)?;
+        let shipper = WalShipper::bind("127.0.0.1:0", &leader)?.spawn()?;
+
+        let mut follower = Follower::new(LSMBuilder::new().segment_size(6).inmemory_capacity(3).build(), 0);
+        let mut stream = ReplicationStream::connect(shipper.addr(), 0)?;
+        follower.catch_up(&mut stream, leader.latest_sequence())?;
+        assert_eq!(follower.applied(), leader.latest_sequence());
+        assert_eq!(follower.scan("", "~")?, leader.scan("", "~")?);
+
+        //records logged after the follower connected are shipped as they come
+        write(&mut leader, 20, 50)?;
+        leader.delete_range("k030", "k040")?;
+        follower.catch_up(&mut stream, leader.latest_sequence())?;
+        assert_eq!(follower.scan("", "~")?, leader.scan("", "~")?);
+        assert_eq!(follower.read("k035")?, None);
+
+        //a follower seeded from a checkpoint only needs what came after it
+        let standby = tempdir()?;
+        let checkpoint = standby.path().join("checkpoint");
+        leader.checkpoint(&checkpoint)?;
+        let engine = builder().open(&checkpoint)?;
+        let applied = engine.latest_sequence();
+        assert_eq!(applied, leader.latest_sequence());
+        let mut seeded = Follower::new(engine, applied);
+        write(&mut leader, 50, 70)?;
+        seeded.catch_up(&mut ReplicationStream::connect(shipper.addr(), applied)?, leader.latest_sequence())?;
+        assert_eq!(seeded.scan("", "~")?, leader.scan("", "~")?);
+
+        assert!(matches!(follower.apply(follower.applied() + 2, Record::Delete { key: "k".to_owned() }), Err(Error::ReplicationGap { .. })));
+
+        //ingests are logged, but followers can't apply them
+        let path = standby.path().join("external");
+        let mut writer = crate::SstWriter::create(&path)?;
+        writer.put("x".to_owned(), "ingested".to_owned())?;
+        writer.finish()?;
+        leader.ingest_external_files(&[&path])?;
+        let ingest = leader.latest_sequence();
+        write(&mut leader, 70, 75)?;
+        let result = seeded.catch_up(&mut ReplicationStream::connect(shipper.addr(), seeded.applied())?, leader.latest_sequence());
+        assert!(matches!(result, Err(Error::IngestNotReplicated { sequence }) if sequence == ingest));
+        assert_eq!(seeded.applied(), ingest - 1);
+        drop(leader);
+        let mut reopened = builder().open(dir.path())?;
+        assert_eq!((reopened.latest_sequence(), reopened.read("x")?), (ingest + 5, Some("ingested".to_owned())));
+        drop(stream);
+        shipper.shutdown()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+use crate::net::{Listener, Protocol};
+use crate::{ConcurrentEngine, Error, Result, WriteBatch};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// A value in the Redis serialization protocol (RESP), as far as this server needs it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    //none is the null bulk string
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+/// The longest bulk string read, so that a length announced by the peer doesn't get allocated before it's checked.
+const MAX_BULK: i64 = 16 << 20;
+/// The most values an array read may hold.
+const MAX_ARRAY: i64 = 1 << 24;
+
+fn protocol_error<T>(reason: impl Into<String>) -> Result<T> {
+    return Err(Error::ProtocolError { reason: reason.into() });
+}
+
+/// Reads a line without its trailing `\r\n`; none at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    match line.strip_suffix("\r\n").or_else(|| line.strip_suffix('\n')) {
+        Some(stripped) => return Ok(Some(stripped.to_owned())),
+        None => return protocol_error("the stream ended halfway through a line"),
+    }
+}
+
+fn read_value<R: BufRead>(reader: &mut R) -> Result<Option<Value>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let (kind, rest) = line.split_at(line.chars().next().map_or(0, char::len_utf8));
+    let number = || rest.parse::<i64>().or_else(|_| protocol_error(format!("{:?} is not a number", rest)));
+    let value = match kind {
+        "+" => Value::Simple(rest.to_owned()),
+        "-" => Value::Error(rest.to_owned()),
+        ":" => Value::Integer(number()?),
+        "$" if number()? < 0 => Value::Bulk(None),
+        "$" if number()? > MAX_BULK => return protocol_error(format!("bulk strings are at most {} bytes long", MAX_BULK)),
+        "$" => {
+            let mut bytes = vec![0; number()? as usize + 2];
+            reader.read_exact(&mut bytes)?;
+            if !bytes.ends_with(b"\r\n") {
+                return protocol_error("a bulk string is longer than announced");
+            }
+            bytes.truncate(bytes.len() - 2);
+            match String::from_utf8(bytes) {
+                Ok(string) => Value::Bulk(Some(string)),
+                Err(_) => return protocol_error("keys and values must be UTF-8"),
+            }
+        }
+        "*" if number()? > MAX_ARRAY => return protocol_error(format!("arrays hold at most {} values", MAX_ARRAY)),
+        "*" => {
+            let mut values = vec![];
+            for _ in 0..number()?.max(0) {
+                match read_value(reader)? {
+                    Some(value) => values.push(value),
+                    None => return protocol_error("the stream ended halfway through an array"),
+                }
+            }
+            Value::Array(values)
+        }
+        _ => return protocol_error(format!("unexpected reply {:?}", line)),
+    };
+    return Ok(Some(value));
+}
+
+fn write_value(buffer: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Simple(string) => buffer.extend(format!("+{}\r\n", string).bytes()),
+        //an error is a single line, whatever went wrong
+        Value::Error(message) => buffer.extend(format!("-{}\r\n", message.replace(['\r', '\n'], " ")).bytes()),
+        Value::Integer(number) => buffer.extend(format!(":{}\r\n", number).bytes()),
+        Value::Bulk(None) => buffer.extend(b"$-1\r\n"),
+        Value::Bulk(Some(string)) => buffer.extend(format!("${}\r\n{}\r\n", string.len(), string).bytes()),
+        Value::Array(values) => {
+            buffer.extend(format!("*{}\r\n", values.len()).bytes());
+            for value in values {
+                write_value(buffer, value);
+            }
+        }
+    }
+}
+
+fn send(stream: &mut TcpStream, value: &Value) -> io::Result<()> {
+    let mut buffer = vec![];
+    write_value(&mut buffer, value);
+    return stream.write_all(&buffer);
+}
+
+/// Reads a command, sent as an array of bulk strings the way Redis clients do, or as an inline line of words the way
+/// people typing into `nc` do.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    if reader.fill_buf()?.first().is_some_and(|first| *first != b'*') {
+        return Ok(read_line(reader)?.map(|line| line.split_whitespace().map(str::to_owned).collect()));
+    }
+    let values = match read_value(reader)? {
+        Some(Value::Array(values)) => values,
+        Some(_) => return protocol_error("a command must be an array"),
+        None => return Ok(None),
+    };
+    let mut words = vec![];
+    for value in values {
+        match value {
+            Value::Bulk(Some(word)) => words.push(word),
+            _ => return protocol_error("a command must be an array of bulk strings"),
+        }
+    }
+    return Ok(Some(words));
+}
+
+fn execute(engine: &ConcurrentEngine, command: &[String]) -> Result<Value> {
+    let name = command.first().map(|name| name.to_ascii_uppercase()).unwrap_or_default();
+    let args = command.iter().skip(1).map(String::as_str).collect::<Vec<_>>();
+    let count = |found: Vec<Option<String>>| Value::Integer(found.iter().filter(|value| value.is_some()).count() as i64);
+    let reply = match (name.as_str(), args.as_slice()) {
+        ("PING", []) => Value::Simple("PONG".to_owned()),
+        ("GET", [key]) => Value::Bulk(engine.read(key)?),
+        ("SET", [key, value]) => {
+            engine.write(key.to_string(), value.to_string())?;
+            Value::Simple("OK".to_owned())
+        }
+        ("DEL", keys) if !keys.is_empty() => {
+            let found = engine.multi_get(keys)?;
+            let mut batch = WriteBatch::new();
+            for key in keys {
+                batch.delete(key);
+            }
+            engine.write_batch(batch)?;
+            count(found)
+        }
+        ("EXISTS", keys) if !keys.is_empty() => count(engine.multi_get(keys)?),
+        ("SCAN", [start, end]) => {
+            let pairs = engine.scan(start, end)?.into_iter();
+            Value::Array(pairs.flat_map(|(key, value)| [Value::Bulk(Some(key)), Value::Bulk(Some(value))]).collect())
+        }
+        ("", _) => Value::Error("ERR empty command".to_owned()),
+        _ => Value::Error(format!("ERR unknown command or wrong number of arguments for '{}'", name)),
+    };
+    return Ok(reply);
+}
+
+fn serve_connection(engine: &ConcurrentEngine, stream: TcpStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let reply = match read_command(&mut reader) {
+            Ok(None) => return Ok(()),
+            Ok(Some(command)) => execute(engine, &command).unwrap_or_else(|error| Value::Error(format!("ERR {}", error))),
+            //the stream can't be trusted past a malformed command
+            Err(error) => {
+                send(&mut writer, &Value::Error(format!("ERR {}", error)))?;
+                return Err(error);
+            }
+        };
+        send(&mut writer, &reply)?;
+    }
+}
+
+/// Serves an engine over TCP, speaking the subset of the Redis protocol its [`Client`] needs, so `redis-cli` works
+/// too: `GET key`, `SET key value`, `DEL key...`, `EXISTS key...` and `PING`. `SCAN start end` is this server's own:
+/// it replies with the keys in `start..end` and their values, alternating in a flat array.
+///
+/// Every connection gets a thread of its own, and they all share the one engine. A command with a bulk string longer
+/// than 16 MiB is refused, and the connection closed.
+pub type Server = Listener<Resp>;
+
+/// The Redis protocol, as a [`Server`] speaks it.
+pub struct Resp;
+
+impl Protocol for Resp {
+    type Shared = ConcurrentEngine;
+
+    fn serve(engine: &ConcurrentEngine, stream: TcpStream) -> Result<()> {
+        return serve_connection(engine, stream);
+    }
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: ConcurrentEngine) -> Result<Self> {
+        return Listener::bind_to(addr, engine);
+    }
+}
+
+/// A connection to a [`Server`], or to anything else speaking the same subset of the Redis protocol.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        writer.set_nodelay(true)?;
+        return Ok(Client { reader: BufReader::new(writer.try_clone()?), writer });
+    }
+
+    fn call(&mut self, command: &[&str]) -> Result<Value> {
+        let command = Value::Array(command.iter().map(|word| Value::Bulk(Some(word.to_string()))).collect());
+        send(&mut self.writer, &command)?;
+        return match read_value(&mut self.reader)? {
+            Some(Value::Error(message)) => Err(Error::ServerError { message }),
+            Some(reply) => Ok(reply),
+            None => protocol_error("the server hung up"),
+        };
+    }
+
+    fn call_for_integer(&mut self, command: &[&str]) -> Result<usize> {
+        return match self.call(command)? {
+            Value::Integer(number) => Ok(number as usize),
+            reply => protocol_error(format!("expected an integer, got {:?}", reply)),
+        };
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.call(&["PING"])?;
+        return Ok(());
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        return match self.call(&["GET", key])? {
+            Value::Bulk(value) => Ok(value),
+            reply => protocol_error(format!("expected a bulk string, got {:?}", reply)),
+        };
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.call(&["SET", key, value])?;
+        return Ok(());
+    }
+
+    /// Deletes every one of `keys` at once, returning how many of them were there.
+    pub fn del(&mut self, keys: &[&str]) -> Result<usize> {
+        return self.call_for_integer(&[&["DEL"][..], keys].concat());
+    }
+
+    /// How many of `keys` are there.
+    pub fn exists(&mut self, keys: &[&str]) -> Result<usize> {
+        return self.call_for_integer(&[&["EXISTS"][..], keys].concat());
+    }
+
+    /// The keys in `start..end` and their values, in order.
+    pub fn scan(&mut self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        let values = match self.call(&["SCAN", start, end])? {
+            Value::Array(values) => values,
+            reply => return protocol_error(format!("expected an array, got {:?}", reply)),
+        };
+        let mut pairs = vec![];
+        for pair in values.chunks(2) {
+            match pair {
+                [Value::Bulk(Some(key)), Value::Bulk(Some(value))] => pairs.push((key.clone(), value.clone())),
+                _ => return protocol_error("expected keys and values, alternating"),
+            }
+        }
+        return Ok(pairs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LSMBuilder, ServerHandle};
+    use std::thread;
+
+    fn serve() -> Result<ServerHandle> {
+        let engine = ConcurrentEngine::new(LSMBuilder::new().segment_size(5).inmemory_capacity(5).build());
+        return Server::bind("127.0.0.1:0", engine)?.spawn();
+    }
+
+    #[test]
+    fn test_commands() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let server = serve()?;
+        let mut client = Client::connect(server.addr())?;
+        client.ping()?;
+        assert_eq!(client.get("k1")?, None);
+        for i in 0..20 {
+            client.set(&format!("k{:02}", i), &format!("value {}\r\nwith a line break", i))?;
+        }
+        assert_eq!(client.get("k03")?, Some("value 3\r\nwith a line break".to_owned()));
+        assert_eq!(client.exists(&["k01", "k02", "nope"])?, 2);
+        assert_eq!(client.del(&["k01", "k02", "nope"])?, 2);
+        assert_eq!(client.exists(&["k01"])?, 0);
+        let scanned = client.scan("k00", "k05")?;
+        assert_eq!(scanned.iter().map(|(key, _value)| key.as_str()).collect::<Vec<_>>(), vec!["k00", "k03", "k04"]);
+        assert!(matches!(client.call(&["GET"]), Err(Error::ServerError { .. })));
+        assert!(matches!(client.call(&["FLUSHALL"]), Err(Error::ServerError { .. })));
+        assert!(matches!(client.set("k05", &WriteBatch::new().encode()), Err(Error::ServerError { .. })));
+        //still usable after an error
+        assert_eq!(client.get("k04")?, Some("value 4\r\nwith a line break".to_owned()));
+        server.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_clients_share_the_engine() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let server = serve()?;
+        let addr = server.addr();
+        let writers = (0..4)
+            .map(|writer| {
+                thread::spawn(move || -> Result<()> {
+                    let mut client = Client::connect(addr)?;
+                    for i in 0..25 {
+                        client.set(&format!("{}-{:02}", writer, i), "v")?;
+                    }
+                    return Ok(());
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        let mut client = Client::connect(addr)?;
+        assert_eq!(client.scan("", "~")?.len(), 100);
+
+        //inline commands, the way a person would type them
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(b"SET typed by-hand\r\nGET typed\r\n")?;
+        let mut reader = BufReader::new(stream);
+        assert_eq!(read_value(&mut reader)?, Some(Value::Simple("OK".to_owned())));
+        assert_eq!(read_value(&mut reader)?, Some(Value::Bulk(Some("by-hand".to_owned()))));
+
+        //announced lengths past the limits are refused before anything is allocated for them
+        for command in [format!("*1\r\n${}\r\n", MAX_BULK + 1), format!("*{}\r\n", MAX_ARRAY + 1)] {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(command.as_bytes())?;
+            let mut reader = BufReader::new(stream);
+            assert!(matches!(read_value(&mut reader)?, Some(Value::Error(message)) if message.contains("at most")));
+            assert_eq!(read_value(&mut reader)?, None);
+        }
+        Ok(())
+    }
+}
@@ -3,25 +3,36 @@ use std::fs::File;
 use binary_heap_plus::*;
 
 use std::fs::OpenOptions;
-use std::io::BufRead;
+use std::io::{BufRead, Write};
 use std::io::BufReader;
+use std::path::Path;
 use std::time::Instant;
 
 use std::io;
 use thiserror::Error;
 
-use crate::kv::{KVFileIterator, KVFileWriter, KVPair};
+use crate::compaction::{CompactionFilter, CompactionStats, Decision};
+use crate::checksum::Checksum;
+use crate::kv::{KVFileIterator, KVPair, LineReader};
+use crate::merge::{self, MergeOperator};
+use crate::range_tombstone::RangeTombstone;
+use crate::rate_limiter::RateLimiter;
+use crate::record::Record;
+use crate::TOMBSTONE_VALUE;
 use std::cmp::Ordering;
 use std::convert::TryFrom;
 use std::iter::Peekable;
 
-type Result<T> = std::result::Result<T, SstError>;
+pub(crate) type Result<T> = std::result::Result<T, SstError>;
 
 #[derive(Error, Debug)]
 pub enum SstError {
     #[error("Attempted to write {} but previous key is {}", current, previous)]
     UnsortedWrite { previous: String, current: String },
 
+    #[error("Attempted to write {} twice", key)]
+    DuplicateKey { key: String },
+
     #[error(transparent)]
     Disconnect(#[from] io::Error),
 
@@ -34,9 +45,15 @@ pub enum SstError {
 
 pub struct Segment {
     fd: File,
+    //the file's number in the data directory, for segments that outlive the engine
+    number: Option<u64>,
     size: usize,
+    tombstones: usize,
+    first_key: Option<String>,
     previous_key: Option<String>,
     created_at: Instant,
+    //of every byte written, to check the file against later
+    checksum: Checksum,
 }
 
 impl KVFileIterator for Segment {
@@ -45,8 +62,6 @@ impl KVFileIterator for Segment {
     }
 }
 
-impl KVFileWriter for Segment {}
-
 struct MetaKey {
     key: String,
     value: String,
@@ -69,23 +84,26 @@ impl PartialEq for MetaKey {
 }
 
 impl PartialOrd for MetaKey {
-    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
-        return Some(self.cmp(self));
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
     }
 }
 
 impl Eq for MetaKey {}
 
-struct SstMerger<I: Iterator<Item = KVPair>> {
+struct SstMerger<'a, I: Iterator<Item = KVPair>> {
     heap: BinaryHeap<MetaKey, MinComparator>,
     segment_iterators: Vec<Peekable<I>>,
-    previous_key: Option<String>,
+    merge_operator: Option<&'a dyn MergeOperator>,
+    range_tombstones: &'a [RangeTombstone],
 }
 
-impl<I: Iterator<Item = KVPair>> SstMerger<I> {
+impl<'a, I: Iterator<Item = KVPair>> SstMerger<'a, I> {
     fn new(
         mut heap: BinaryHeap<MetaKey, MinComparator>,
         mut segment_iterators_with_timestamp: Vec<(Peekable<I>, Instant)>,
+        merge_operator: Option<&'a dyn MergeOperator>,
+        range_tombstones: &'a [RangeTombstone],
     ) -> Self {
         //initialize the heap
         for (index, (it, timestamp)) in segment_iterators_with_timestamp.iter_mut().enumerate() {
@@ -106,51 +124,95 @@ impl<I: Iterator<Item = KVPair>> SstMerger<I> {
                 .into_iter()
                 .map(|x| x.0)
                 .collect(),
-            previous_key: None,
+            merge_operator,
+            range_tombstones,
         };
     }
+
+    /// Pops the smallest key off the heap, replacing it with the next entry of the segment it came from.
+    fn pop(&mut self) -> Option<MetaKey> {
+        let meta_key = self.heap.pop()?;
+        let segment_iterator = &mut self.segment_iterators[meta_key.which_segment];
+        if let Some(next) = segment_iterator.next() {
+            self.heap.push(MetaKey {
+                key: next.key,
+                value: next.value,
+                timestamp: meta_key.timestamp,
+                which_segment: meta_key.which_segment,
+            });
+        }
+        return Some(meta_key);
+    }
+
+    /// Collapses every version of a key (newest first) into the single value that survives the merge.
+    /// Pending merge operands are folded into the newest complete value beneath them.
+    fn resolve(&self, key: &str, mut versions: Vec<String>) -> String {
+        let operator = match self.merge_operator {
+            Some(operator) => operator,
+            None => return versions.swap_remove(0),
+        };
+
+        let mut pending = vec![];
+        let mut base = None;
+        for value in versions {
+            match merge::decode_operands(&value) {
+                Some(operands) => pending.push(operands),
+                None => {
+                    base = Some(value);
+                    break;
+                }
+            }
+        }
+        if pending.is_empty() {
+            return base.unwrap();
+        }
+
+        let operands = pending.into_iter().rev().flatten().collect::<Vec<_>>();
+        let existing = base.as_deref().filter(|value| *value != TOMBSTONE_VALUE.as_str());
+        return operator.full_merge(key, existing, &operands);
+    }
 }
 
-impl<I: Iterator<Item = KVPair>> Iterator for SstMerger<I> {
+impl<'a, I: Iterator<Item = KVPair>> Iterator for SstMerger<'a, I> {
     type Item = KVPair;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.heap.is_empty() {
-            let meta_key = self.heap.pop().unwrap();
-            let segment_iterator = &mut self.segment_iterators[meta_key.which_segment];
-            if Some(meta_key.key.clone()) == self.previous_key {
-                continue;
-            }
-            self.previous_key = Some(meta_key.key.clone());
-            if segment_iterator.peek().is_some() {
-                let next = segment_iterator.next().unwrap();
-                self.heap.push(MetaKey {
-                    key: next.key,
-                    value: next.value,
-                    timestamp: meta_key.timestamp,
-                    which_segment: meta_key.which_segment,
-                });
-                return Some(KVPair {
-                    key: meta_key.key,
-                    value: meta_key.value,
-                });
+        loop {
+            let newest = self.pop()?;
+            let mut versions = vec![newest];
+            while self.heap.peek().is_some_and(|next| next.key == versions[0].key) {
+                versions.push(self.pop().unwrap());
             }
 
-            return Some(KVPair {
-                key: meta_key.key,
-                value: meta_key.value,
-            });
+            //versions shadowed by a range deletion are dropped for good
+            let tombstones = self.range_tombstones;
+            let key = versions[0].key.clone();
+            let live = versions
+                .into_iter()
+                .filter(|version| !tombstones.iter().any(|t| t.covers(&version.key, version.timestamp)))
+                .map(|version| version.value)
+                .collect::<Vec<_>>();
+            if live.is_empty() {
+                continue;
+            }
+            let value = self.resolve(&key, live);
+            return Some(KVPair { key, value });
         }
-        None
     }
 }
 
-pub fn merge<F: FnMut(usize, u64, String) -> ()>(
+#[allow(clippy::too_many_arguments)]
+pub fn merge<F: FnMut(usize, u64, String)>(
     mut segments: Vec<Segment>,
     segment_size: usize,
+    new_segment: &dyn Fn() -> Result<Segment>,
+    merge_operator: Option<&dyn MergeOperator>,
+    compaction_filter: Option<&dyn CompactionFilter>,
+    range_tombstones: &[RangeTombstone],
+    rate_limiter: Option<&RateLimiter>,
     mut callback_on_write: F,
-) -> Result<Vec<Segment>> {
-    let segment_timestamps = segments.iter().map(|s| s.created_at).collect::<Vec<_>>();
+) -> Result<(Vec<Segment>, CompactionStats)> {
+    let segment_timestamps = segments.iter().map(|s| s.timestamp()).collect::<Vec<_>>();
 
     let iterators = segments
         .iter_mut()
@@ -164,42 +226,106 @@ pub fn merge<F: FnMut(usize, u64, String) -> ()>(
         .zip(segment_timestamps)
         .collect::<Vec<_>>();
 
-    let merger = SstMerger::new(heap, iterator_with_timestamp);
+    let merger = SstMerger::new(heap, iterator_with_timestamp, merge_operator, range_tombstones);
     let mut res = vec![];
-    let mut segment = Segment::temp();
+    //created on the first write, so that a merge with no output leaves no empty file behind
+    let mut current: Option<Segment> = None;
     let mut segment_count: usize = 0;
-
-    for kv in merger.into_iter() {
-        if segment.size() == segment_size {
-            res.push(segment);
-            segment = Segment::temp();
+    let mut stats = CompactionStats::default();
+
+    for mut kv in merger.into_iter() {
+        //tombstones and unresolved operands aren't user values, so they are never handed to the filter
+        let is_live = kv.value != *TOMBSTONE_VALUE && merge::decode_operands(&kv.value).is_none();
+        if let Some(filter) = compaction_filter.filter(|_| is_live) {
+            let decision = filter.filter(&kv.key, &kv.value);
+            stats.record(&decision);
+            match decision {
+                Decision::Keep => {}
+                Decision::Remove => continue,
+                Decision::ChangeValue(value) => kv.value = value,
+            }
+        }
+        if current.as_ref().is_some_and(|segment| segment.size() == segment_size) {
+            res.extend(current.take());
             segment_count += 1;
         }
+        if current.is_none() {
+            current = Some(new_segment()?);
+        }
+        let segment = current.as_mut().unwrap();
         let cloned_key = kv.key.clone();
         let offset = segment.write(kv)?;
+        if let Some(limiter) = rate_limiter {
+            limiter.request(segment.tell()? - offset);
+        }
         callback_on_write(segment_count, offset, cloned_key);
     }
-    if segment.size() > 0 {
-        res.push(segment);
+    res.extend(current);
+    //output segments are only ever appended to, so each one ends where it was last written
+    for segment in res.iter_mut() {
+        stats.bytes_written += segment.tell()?;
     }
-    Ok(res)
+    Ok((res, stats))
 }
 
 impl Segment {
+    #[allow(dead_code)]
     pub fn new(path: &str) -> Segment {
         return Segment {
             fd: OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(path)
                 .unwrap(),
+            number: None,
             size: 0,
+            tombstones: 0,
+            first_key: None,
             previous_key: None,
             created_at: Instant::now(),
+            checksum: Checksum::default(),
         };
     }
 
+    /// Creates the segment file numbered `number` at `path`, replacing any existing one.
+    pub fn create(path: &Path, number: u64) -> Result<Segment> {
+        let fd = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let mut segment = Segment::with_file(fd);
+        segment.number = Some(number);
+        return Ok(segment);
+    }
+
+    /// Opens the existing segment file numbered `number`, dating its entries to `created_at`. The file is read
+    /// through once to recover its key range and counts, handing every key and its offset to `on_entry`.
+    pub fn open<F: FnMut(u64, &str)>(path: &Path, number: u64, created_at: Instant, mut on_entry: F) -> Result<Segment> {
+        let fd = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut segment = Segment::with_file(fd);
+        let mut lines = LineReader::new(BufReader::new(&segment.fd));
+        for (offset, kv) in lines.by_ref() {
+            let kv = kv?;
+            segment.validate(&kv.key)?;
+            on_entry(offset, &kv.key);
+            if segment.first_key.is_none() {
+                segment.first_key = Some(kv.key.clone());
+            }
+            if kv.value == *TOMBSTONE_VALUE {
+                segment.tombstones += 1;
+            }
+            segment.previous_key = Some(kv.key);
+            segment.size += 1;
+        }
+        segment.checksum = Checksum::resume(lines.checksum());
+        segment.number = Some(number);
+        segment.created_at = created_at;
+        return Ok(segment);
+    }
+
+    pub fn number(&self) -> Option<u64> {
+        return self.number;
+    }
+
     pub fn temp() -> Segment {
         let temp = tempfile::tempfile().unwrap();
         return Segment::with_file(temp);
@@ -209,12 +335,21 @@ impl Segment {
         return self.created_at;
     }
 
+    /// Dates the segment's entries to now, making them newer than everything written before.
+    pub fn renew_timestamp(&mut self) {
+        self.created_at = Instant::now();
+    }
+
     pub fn with_file(f: File) -> Segment {
         return Segment {
             fd: f,
+            number: None,
             size: 0,
+            tombstones: 0,
+            first_key: None,
             previous_key: None,
             created_at: Instant::now(),
+            checksum: Checksum::default(),
         };
     }
 
@@ -222,7 +357,7 @@ impl Segment {
         if self
             .previous_key
             .as_ref()
-            .map_or(false, |prev| prev.as_str() > key)
+            .is_some_and(|prev| prev.as_str() > key)
         {
             return Err(SstError::UnsortedWrite {
                 previous: self.previous_key.as_ref().unwrap().to_string(),
@@ -235,16 +370,79 @@ impl Segment {
     pub fn write(&mut self, kv: KVPair) -> Result<u64> {
         //check if the previously written key is bigger than the current key
         self.validate(&kv.key)?;
+        if self.first_key.is_none() {
+            self.first_key = Some(kv.key.clone());
+        }
+        if kv.value == *TOMBSTONE_VALUE {
+            self.tombstones += 1;
+        }
         self.previous_key = Some(kv.key.clone());
-        let current_offset = self.persist(kv)?;
+        let mut line = serde_json::to_vec(&kv)?;
+        line.push(b'\n');
+        let current_offset = self.tell()?;
+        self.fd.write_all(&line)?;
+        self.checksum.update(&line);
         self.size += 1;
         return Ok(current_offset);
     }
 
-    fn size(&self) -> usize {
+    pub fn size(&self) -> usize {
         return self.size;
     }
 
+    pub fn tombstones(&self) -> usize {
+        return self.tombstones;
+    }
+
+    pub fn first_key(&self) -> Option<&str> {
+        return self.first_key.as_deref();
+    }
+
+    pub fn last_key(&self) -> Option<&str> {
+        return self.previous_key.as_deref();
+    }
+
+    /// Whether `key` falls between the first and last keys written, i.e. whether searching for it can succeed.
+    pub fn in_key_range(&self, key: &str) -> bool {
+        return self.first_key().is_some_and(|first| first <= key) && self.last_key().is_some_and(|last| key <= last);
+    }
+
+    /// Writes a copy of the segment's file to `path`.
+    pub fn copy_to(&mut self, path: &Path) -> Result<()> {
+        let current = self.tell()?;
+        self.reset()?;
+        let mut copy = File::create(path)?;
+        io::copy(&mut self.fd, &mut copy)?;
+        copy.sync_all()?;
+        self.seek(current)?;
+        return Ok(());
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.fd.sync_all()?;
+        return Ok(());
+    }
+
+    /// The checksum of the segment's file, as written.
+    pub fn checksum(&self) -> u64 {
+        return self.checksum.value();
+    }
+
+    /// Hands a reader over the segment's file, from the start, to `read`, putting the segment's position back after.
+    pub(crate) fn read_entries<T, F: FnOnce(SegmentReader) -> T>(&mut self, read: F) -> Result<T> {
+        let current = self.tell()?;
+        self.reset()?;
+        //the clone shares the position, hence the reset before and the seek after
+        let result = read(SegmentReader::with_file(self.fd.try_clone()?));
+        self.seek(current)?;
+        return Ok(result);
+    }
+
+    pub fn bytes(&self) -> Result<u64> {
+        return Ok(self.fd.metadata()?.len());
+    }
+
+    #[allow(dead_code)]
     pub fn at(&mut self, pos: u64) -> Result<Option<String>> {
         let current = self.tell()?;
         self.seek(pos)?;
@@ -270,6 +468,40 @@ impl Segment {
         return self.search_from(key, 0);
     }
 
+    /// Looks up all of the sorted `keys` in a single forward scan from `offset`. Returns the entries found, along
+    /// with the keys past the end of the segment, which may still live in the next one.
+    pub fn search_many_from<'k>(&mut self, keys: &[&'k str], offset: u64) -> Result<(Vec<KVPair>, Vec<&'k str>)> {
+        let current_pos = self.tell()?;
+        self.seek(offset)?;
+        let mut wanted = keys.iter().copied().peekable();
+        let mut found = vec![];
+        for kv in self.read() {
+            while wanted.next_if(|key| *key < kv.key.as_str()).is_some() {}
+            if wanted.next_if(|key| *key == kv.key).is_some() {
+                found.push(kv);
+            }
+            if wanted.peek().is_none() {
+                break;
+            }
+        }
+        let remaining = wanted.collect();
+        self.seek(current_pos)?;
+        return Ok((found, remaining));
+    }
+
+    /// Collects the entries with keys in `start..end`, scanning forward from `offset`.
+    pub fn range_from(&mut self, start: &str, end: &str, offset: u64) -> Result<Vec<KVPair>> {
+        let current_pos = self.tell()?;
+        self.seek(offset)?;
+        let pairs = self
+            .read()
+            .skip_while(|kv| kv.key.as_str() < start)
+            .take_while(|kv| kv.key.as_str() < end)
+            .collect();
+        self.seek(current_pos)?;
+        return Ok(pairs);
+    }
+
     pub fn read(&self) -> impl Iterator<Item = KVPair> + '_ {
         let reader = BufReader::new(&self.fd);
         return reader.lines().map(|string| {
@@ -284,9 +516,46 @@ impl Segment {
     }
 }
 
+/// Reads the entries of a segment file one by one, along with their offsets, for tools looking at it from outside
+/// the engine.
+pub struct SegmentReader {
+    lines: LineReader<BufReader<File>>,
+}
+
+impl SegmentReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        return Ok(SegmentReader::with_file(File::open(path)?));
+    }
+
+    pub(crate) fn with_file(file: File) -> Self {
+        return SegmentReader { lines: LineReader::new(BufReader::new(file)) };
+    }
+
+    /// The offset of the next entry.
+    pub fn offset(&self) -> u64 {
+        return self.lines.offset();
+    }
+
+    /// The checksum of the entries read so far; once they all are, the checksum of the whole file.
+    pub fn checksum(&self) -> u64 {
+        return self.lines.checksum();
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = crate::Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (offset, kv) = self.lines.next()?;
+        return Some(kv.map(|kv| (offset, Record::decode(kv))).map_err(crate::Error::from));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::kv::{KVFileIterator, KVPair};
+    use crate::merge::{encode_operands, MergeOperator};
+    use crate::range_tombstone::RangeTombstone;
     use crate::sst::{merge, Segment};
 
     extern crate tempfile;
@@ -434,7 +703,7 @@ mod tests {
             value: "v2".to_owned(),
         })?;
         let v = vec![sst_1, sst_2];
-        let mut merged = merge(v, 20, |_index, _offset, _| {})?;
+        let (mut merged, _) = merge(v, 20, &|| Ok(Segment::temp()), None, None, &[], None, |_index, _offset, _| {})?;
         assert_eq!(merged.len(), 1);
         let mut segment = merged.pop().unwrap();
         let pairs: Vec<_> = segment
@@ -466,7 +735,7 @@ mod tests {
             value: "v2".to_owned(),
         })?;
         let v = vec![sst_1, sst_2];
-        let mut merged = merge(v, 100, |_index, _offset, _| {})?;
+        let (mut merged, _) = merge(v, 100, &|| Ok(Segment::temp()), None, None, &[], None, |_index, _offset, _| {})?;
         let expected = vec![("k1".to_owned(), "v2".to_owned())];
         let actual: Vec<_> = merged[0]
             .read_from_start()?
@@ -475,4 +744,105 @@ mod tests {
         assert_eq!(expected, actual);
         Ok(())
     }
+
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn full_merge(&self, _key: &str, existing: Option<&str>, operands: &[String]) -> String {
+            let mut values = existing.map(|v| vec![v.to_owned()]).unwrap_or_default();
+            values.extend_from_slice(operands);
+            return values.join(",");
+        }
+    }
+
+    #[test]
+    fn test_merge_folds_operands() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sst_1 = Segment::temp();
+        let mut sst_2 = Segment::temp();
+        let mut sst_3 = Segment::temp();
+        sst_1.write(KVPair {
+            key: "k1".to_owned(),
+            value: "a".to_owned(),
+        })?;
+        sst_1.write(KVPair {
+            key: "k2".to_owned(),
+            value: "v2".to_owned(),
+        })?;
+        sst_2.write(KVPair {
+            key: "k1".to_owned(),
+            value: encode_operands(&["b".to_owned()]),
+        })?;
+        sst_3.write(KVPair {
+            key: "k1".to_owned(),
+            value: encode_operands(&["c".to_owned(), "d".to_owned()]),
+        })?;
+        sst_3.write(KVPair {
+            key: "k3".to_owned(),
+            value: encode_operands(&["e".to_owned()]),
+        })?;
+        let v = vec![sst_1, sst_2, sst_3];
+        let (mut merged, _) = merge(v, 100, &|| Ok(Segment::temp()), Some(&Append), None, &[], None, |_index, _offset, _| {})?;
+        let actual: Vec<_> = merged[0]
+            .read_from_start()?
+            .map(|kv| (kv.key, kv.value))
+            .collect();
+        let expected = vec![
+            ("k1".to_owned(), "a,b,c,d".to_owned()),
+            ("k2".to_owned(), "v2".to_owned()),
+            ("k3".to_owned(), "e".to_owned()),
+        ];
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_applies_range_tombstones() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sst_1 = Segment::temp();
+        for k in ["k1", "k2", "k3", "k4"] {
+            sst_1.write(KVPair {
+                key: k.to_owned(),
+                value: "old".to_owned(),
+            })?;
+        }
+        let tombstones = vec![RangeTombstone::new("k2", "k4")];
+        let mut sst_2 = Segment::temp();
+        sst_2.write(KVPair {
+            key: "k3".to_owned(),
+            value: "new".to_owned(),
+        })?;
+        let v = vec![sst_1, sst_2];
+        let (mut merged, _) = merge(v, 100, &|| Ok(Segment::temp()), None, None, &tombstones, None, |_index, _offset, _| {})?;
+        let actual: Vec<_> = merged[0]
+            .read_from_start()?
+            .map(|kv| (kv.key, kv.value))
+            .collect();
+        let expected = vec![
+            ("k1".to_owned(), "old".to_owned()),
+            ("k3".to_owned(), "new".to_owned()),
+            ("k4".to_owned(), "old".to_owned()),
+        ];
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_many() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sst = Segment::temp();
+        let mut offsets = vec![];
+        for k in ["k1", "k3", "k5", "k7"] {
+            offsets.push(sst.write(KVPair {
+                key: k.to_owned(),
+                value: k.to_owned(),
+            })?);
+        }
+        let (found, remaining) = sst.search_many_from(&["k2", "k3", "k7", "k8", "k9"], offsets[1])?;
+        let found: Vec<_> = found.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(found, vec!["k3".to_owned(), "k7".to_owned()]);
+        assert_eq!(remaining, vec!["k8", "k9"]);
+
+        let (found, remaining) = sst.search_many_from(&["k1", "k4"], 0)?;
+        assert_eq!(found.len(), 1);
+        assert!(remaining.is_empty());
+        Ok(())
+    }
 }
@@ -0,0 +1,113 @@
+use crate::kv::KVPair;
+use crate::sst::{Segment, SstError};
+use crate::record;
+use crate::{Error, Result, TOMBSTONE_VALUE};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    // prefix marking a WAL record as an ingest into a data directory; the rest lists the files ingested
+    static ref INGEST_MARKER: String = {
+        let rng: StdRng = SeedableRng::seed_from_u64(24);
+        rng.sample_iter(&Alphanumeric).take(20).collect::<String>()
+    };
+}
+
+pub(crate) fn encode_ingest(files: &[String]) -> String {
+    let encoded = serde_json::to_string(files).expect("file names are always serializable");
+    return format!("{}{}", *INGEST_MARKER, encoded);
+}
+
+pub(crate) fn decode_ingest(value: &str) -> Option<Vec<String>> {
+    return serde_json::from_str(value.strip_prefix(INGEST_MARKER.as_str())?).ok();
+}
+
+/// Builds a standalone segment file offline, to be loaded with
+/// [`LSMEngine::ingest_external_files`](crate::LSMEngine::ingest_external_files) rather than written key by key.
+///
+/// Keys must be added in strictly ascending order. The file holds plain values and tombstones only, no merge operands.
+pub struct SstWriter {
+    path: PathBuf,
+    segment: Segment,
+}
+
+/// What an [`SstWriter`] wrote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalFileInfo {
+    pub path: PathBuf,
+    pub entries: usize,
+    pub tombstones: usize,
+    pub first_key: Option<String>,
+    pub last_key: Option<String>,
+    pub bytes: u64,
+}
+
+impl SstWriter {
+    /// Creates the file at `path`, replacing anything already there.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path.as_ref())?;
+        return Ok(SstWriter { path: path.as_ref().to_path_buf(), segment: Segment::with_file(file) });
+    }
+
+    pub fn put(&mut self, key: String, value: String) -> Result<()> {
+        return write_strictly_sorted(&mut self.segment, KVPair { key, value }, &self.path).map(|_offset| ());
+    }
+
+    /// Records a tombstone for `key`, deleting whatever the engine held for it once ingested.
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        return self.put(key.to_owned(), TOMBSTONE_VALUE.to_string());
+    }
+
+    /// The number of entries added so far.
+    pub fn entries(&self) -> usize {
+        return self.segment.size();
+    }
+
+    /// Syncs the file to disk and reports what went into it.
+    pub fn finish(self) -> Result<ExternalFileInfo> {
+        self.segment.sync()?;
+        return Ok(ExternalFileInfo {
+            entries: self.segment.size(),
+            tombstones: self.segment.tombstones(),
+            first_key: self.segment.first_key().map(str::to_owned),
+            last_key: self.segment.last_key().map(str::to_owned),
+            bytes: self.segment.bytes()?,
+            path: self.path,
+        });
+    }
+}
+
+/// Writes `kv` unless its key is at or below the last one written: unlike a flush or merge output, an external file
+/// has no timestamps to order several versions of a key by. Values the engine would mistake for merge operands, range
+/// deletes or batches are refused.
+fn write_strictly_sorted(segment: &mut Segment, kv: KVPair, path: &Path) -> Result<u64> {
+    if segment.last_key() == Some(kv.key.as_str()) {
+        return Err(SstError::DuplicateKey { key: kv.key }.into());
+    }
+    if record::is_marked(&kv.value) {
+        let reason = format!("the value of {} is not a plain value or a tombstone", kv.key);
+        return Err(Error::IngestFailed { path: path.display().to_string(), reason });
+    }
+    return Ok(segment.write(kv)?);
+}
+
+/// Copies the external file at `path` into the empty `segment`, checking its entries along the way and handing each
+/// key to `on_key`. Returns the segment along with every `sparse_offset`th key and its offset, for the sparse index.
+pub(crate) fn load<F: FnMut(&str)>(path: &Path, mut segment: Segment, sparse_offset: usize, mut on_key: F) -> Result<(Segment, Vec<(String, u64)>)> {
+    let mut index = vec![];
+    for (count, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let kv = KVPair::try_from(line?)?;
+        let key = kv.key.clone();
+        let offset = write_strictly_sorted(&mut segment, kv, path)?;
+        on_key(&key);
+        if count % sparse_offset == 0 {
+            index.push((key, offset));
+        }
+    }
+    return Ok((segment, index));
+}
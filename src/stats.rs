@@ -0,0 +1,185 @@
+use serde::Serialize;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+/// A latency histogram with fixed buckets.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Histogram {
+    // one count per bucket in `LATENCY_BUCKETS`, plus one for everything slower
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        return Histogram {
+            counts: vec![0; LATENCY_BUCKETS.len() + 1],
+            count: 0,
+            sum: Duration::default(),
+        };
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        return self.count;
+    }
+
+    pub fn sum(&self) -> Duration {
+        return self.sum;
+    }
+
+    /// `(upper bound in seconds, observations at or below it)` for every bucket, ending with `f64::INFINITY`.
+    pub fn cumulative_buckets(&self) -> Vec<(f64, u64)> {
+        let bounds = LATENCY_BUCKETS.iter().copied().chain(std::iter::once(f64::INFINITY));
+        let mut total = 0;
+        return bounds
+            .zip(self.counts.iter())
+            .map(|(bound, count)| {
+                total += count;
+                (bound, total)
+            })
+            .collect();
+    }
+}
+
+/// A snapshot of the engine's counters, as returned by [`LSMEngine::stats`](crate::LSMEngine::stats).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Puts, deletes, merges and range deletions applied, including those inside batches.
+    pub writes: u64,
+    /// Keys looked up, through `read` or `multi_get`.
+    pub reads: u64,
+    /// Lookups answered by the bloom filter alone, without touching any segment.
+    pub bloom_filter_negatives: u64,
+    /// Segments searched across all reads; divide by `reads` for the average per read.
+    pub segments_probed: u64,
+    pub memtable_flushes: u64,
+    pub compactions: u64,
+    /// Bytes written into the segments produced by compactions.
+    pub compaction_bytes_written: u64,
+    pub compaction_time: Duration,
+    /// Writes delayed because merging fell behind, past the slowdown triggers.
+    pub write_slowdowns: u64,
+    /// Writes that waited for the unmerged segments to be merged, past the stop triggers.
+    pub write_stops: u64,
+    /// Time writes spent delayed or stopped.
+    pub write_stall_time: Duration,
+    /// Groups of writes from a [`ConcurrentEngine`](crate::ConcurrentEngine) logged with a single WAL append.
+    pub write_groups: u64,
+    /// Only tracked when enabled with [`LSMBuilder::latency_histograms`](crate::LSMBuilder::latency_histograms).
+    pub read_latency: Option<Histogram>,
+    pub write_latency: Option<Histogram>,
+}
+
+impl Stats {
+    pub(crate) fn with_histograms(enabled: bool) -> Self {
+        let histogram = if enabled { Some(Histogram::default()) } else { None };
+        return Stats {
+            read_latency: histogram.clone(),
+            write_latency: histogram,
+            ..Stats::default()
+        };
+    }
+
+    pub(crate) fn observe_read(&mut self, started: Instant) {
+        if let Some(histogram) = self.read_latency.as_mut() {
+            histogram.record(started.elapsed());
+        }
+    }
+
+    pub(crate) fn observe_write(&mut self, started: Instant) {
+        if let Some(histogram) = self.write_latency.as_mut() {
+            histogram.record(started.elapsed());
+        }
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in histogram.cumulative_buckets() {
+        let bound = if bound.is_infinite() { "+Inf".to_owned() } else { bound.to_string() };
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    let _ = writeln!(out, "{}_sum {}", name, histogram.sum().as_secs_f64());
+    let _ = writeln!(out, "{}_count {}", name, histogram.count());
+}
+
+/// Renders `stats` in the Prometheus text exposition format, ready to be served from a `/metrics` endpoint.
+pub fn prometheus_text(stats: &Stats) -> String {
+    let mut out = String::new();
+    let counters = [
+        ("lsm_writes_total", "Writes applied to the engine.", stats.writes as f64),
+        ("lsm_reads_total", "Keys looked up.", stats.reads as f64),
+        ("lsm_bloom_filter_negatives_total", "Lookups answered by the bloom filter alone.", stats.bloom_filter_negatives as f64),
+        ("lsm_segments_probed_total", "Segments searched by reads.", stats.segments_probed as f64),
+        ("lsm_memtable_flushes_total", "Memtable flushes.", stats.memtable_flushes as f64),
+        ("lsm_compactions_total", "Compactions run.", stats.compactions as f64),
+        ("lsm_compaction_bytes_written_total", "Bytes written by compactions.", stats.compaction_bytes_written as f64),
+        ("lsm_compaction_seconds_total", "Time spent compacting.", stats.compaction_time.as_secs_f64()),
+        ("lsm_write_slowdowns_total", "Writes delayed by the slowdown triggers.", stats.write_slowdowns as f64),
+        ("lsm_write_stops_total", "Writes stopped by the stop triggers.", stats.write_stops as f64),
+        ("lsm_write_stall_seconds_total", "Time writes spent delayed or stopped.", stats.write_stall_time.as_secs_f64()),
+        ("lsm_write_groups_total", "Groups of concurrent writes committed together.", stats.write_groups as f64),
+    ];
+    for (name, help, value) in counters.iter() {
+        write_counter(&mut out, name, help, *value);
+    }
+    if let Some(histogram) = &stats.read_latency {
+        write_histogram(&mut out, "lsm_read_latency_seconds", "Latency of reads.", histogram);
+    }
+    if let Some(histogram) = &stats.write_latency {
+        write_histogram(&mut out, "lsm_write_latency_seconds", "Latency of writes.", histogram);
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(10));
+        let buckets = histogram.cumulative_buckets();
+        assert_eq!(buckets[0], (0.000_01, 1));
+        assert_eq!(buckets[5], (0.005, 2));
+        assert_eq!(buckets.last(), Some(&(f64::INFINITY, 3)));
+        assert_eq!(histogram.count(), 3);
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut stats = Stats::with_histograms(true);
+        stats.writes = 3;
+        stats.observe_read(Instant::now());
+        let text = prometheus_text(&stats);
+        assert!(text.contains("# TYPE lsm_writes_total counter\nlsm_writes_total 3\n"));
+        assert!(text.contains("lsm_read_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("lsm_write_latency_seconds_count 0\n"));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::record::Record;
+use crate::replication::{WalIterator, POLL_INTERVAL};
+use crate::Result;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Every write committed to an engine in a data directory, in order, each with its sequence number: see
+/// [`LSMEngine::subscribe`](crate::LSMEngine::subscribe).
+///
+/// Iterating waits for the next write however long it takes, while [`try_next`](Subscription::try_next) and
+/// [`next_timeout`](Subscription::next_timeout) give up.
+pub struct Subscription {
+    records: WalIterator,
+    last: u64,
+}
+
+impl Subscription {
+    pub(crate) fn new(records: WalIterator, from: u64) -> Self {
+        return Subscription { records, last: from };
+    }
+
+    /// The sequence number of the last write returned, or the one subscribed from. Subscribing from it again after
+    /// a restart carries on with the next write, so it is what a subscriber persists once a write is handled.
+    pub fn last_sequence(&self) -> u64 {
+        return self.last;
+    }
+
+    /// The next write, or none if every write committed so far was returned already.
+    pub fn try_next(&mut self) -> Option<Result<(u64, Record)>> {
+        let next = self.records.next();
+        if let Some(Ok((sequence, _record))) = next.as_ref() {
+            self.last = *sequence;
+        }
+        return next;
+    }
+
+    /// Waits up to `timeout` for the next write.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Result<(u64, Record)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(next) = self.try_next() {
+                return Some(next);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(next) = self.try_next() {
+                return Some(next);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConcurrentEngine, Error, LSMBuilder, WriteBatch};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn builder() -> LSMBuilder {
+        return LSMBuilder::new().segment_size(6).inmemory_capacity(3).wal_retention(100).wal_sync(true);
+    }
+
+    fn put(key: &str, value: &str) -> Record {
+        return Record::Put { key: key.to_owned(), value: value.to_owned() };
+    }
+
+    #[test]
+    fn test_subscribe() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut lsm = builder().open(dir.path())?;
+        let mut subscription = lsm.subscribe(0)?;
+        assert!(subscription.try_next().is_none());
+
+        lsm.write("k1".to_owned(), "v1".to_owned())?;
+        lsm.delete("k1")?;
+        let mut batch = WriteBatch::new();
+        batch.put("k2".to_owned(), "v2".to_owned());
+        batch.delete("k3");
+        lsm.write_batch(batch)?;
+        assert_eq!(subscription.try_next().transpose()?, Some((1, put("k1", "v1"))));
+        assert_eq!(subscription.try_next().transpose()?, Some((2, Record::Delete { key: "k1".to_owned() })));
+        let batch = Record::Batch(vec![put("k2", "v2"), Record::Delete { key: "k3".to_owned() }]);
+        assert_eq!(subscription.try_next().transpose()?, Some((3, batch)));
+        assert!(subscription.next_timeout(Duration::from_millis(20)).is_none());
+        assert_eq!(subscription.last_sequence(), 3);
+
+        //the subscriber persists where it got to, and carries on from there once the engine is back
+        for i in 0..20 {
+            lsm.write(format!("k{:02}", i), i.to_string())?;
+        }
+        for _ in 0..10 {
+            subscription.try_next().transpose()?;
+        }
+        let persisted = subscription.last_sequence();
+        assert_eq!(persisted, 13);
+        drop(subscription);
+        drop(lsm);
+        let mut lsm = builder().open(dir.path())?;
+        lsm.write("k20".to_owned(), "20".to_owned())?;
+        let keys = lsm
+            .subscribe(persisted)?
+            .take(11)
+            .map(|entry| entry.map(|(_sequence, record)| record.key().unwrap().to_owned()))
+            .collect::<crate::Result<Vec<_>>>()?;
+        assert_eq!(keys, (10..=20).map(|i| format!("k{:02}", i)).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribers_only_see_committed_writes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut lsm = builder().open(dir.path())?;
+        let mut subscription = lsm.subscribe(0)?;
+        //logged, but not applied yet
+        lsm.write_to_wal("k1", "v1")?;
+        assert!(subscription.try_next().is_none());
+        lsm.write("k2".to_owned(), "v2".to_owned())?;
+        assert_eq!(subscription.try_next().transpose()?, Some((1, put("k1", "v1"))));
+        assert_eq!(subscription.try_next().transpose()?, Some((2, put("k2", "v2"))));
+        drop(lsm);
+
+        let lsm = builder().wal_sync(false).open(dir.path())?;
+        assert!(matches!(lsm.subscribe(0), Err(Error::NeedsWalSync)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_while_writing() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let engine = ConcurrentEngine::new(builder().open(dir.path())?);
+        let subscription = engine.subscribe(engine.latest_sequence())?;
+        let writers = (0..4)
+            .map(|writer| {
+                let engine = engine.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        engine.write(format!("{}-{:02}", writer, i), i.to_string())?;
+                    }
+                    return crate::Result::Ok(());
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut seen = vec![];
+        for entry in subscription.take(100) {
+            let (sequence, record) = entry?;
+            seen.push((sequence, record.key().unwrap().to_owned()));
+        }
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        assert_eq!(seen.iter().map(|(sequence, _key)| *sequence).collect::<Vec<_>>(), (1..=100).collect::<Vec<_>>());
+        //each writer's own writes come through in the order it made them
+        for writer in 0..4 {
+            let prefix = format!("{}-", writer);
+            let keys = seen.iter().filter(|(_sequence, key)| key.starts_with(&prefix)).map(|(_sequence, key)| key.clone());
+            assert_eq!(keys.collect::<Vec<_>>(), (0..25).map(|i| format!("{}-{:02}", writer, i)).collect::<Vec<_>>());
+        }
+        assert_eq!(engine.latest_sequence(), 100);
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+use crate::lock::{LockManager, TransactionId};
+use crate::{Error, LSMEngine, Result, WriteBatch};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+static NEXT_ENGINE: AtomicU64 = AtomicU64::new(0);
+
+/// Where a transaction began: on which engine, and after how many of its modifications.
+pub(crate) struct Snapshot {
+    engine: u64,
+    sequence: u64,
+}
+
+/// Remembers when keys were last modified, for as long as an open transaction might conflict with them.
+pub(crate) struct ConflictTracker {
+    //tells the engines apart, so that a transaction is only committed against its own
+    engine: u64,
+    sequence: u64,
+    snapshots: Vec<Weak<Snapshot>>,
+    modified_keys: HashMap<String, u64>,
+    modified_ranges: Vec<(String, String, u64)>,
+}
+
+impl Default for ConflictTracker {
+    fn default() -> Self {
+        return ConflictTracker {
+            engine: NEXT_ENGINE.fetch_add(1, Ordering::Relaxed),
+            sequence: 0,
+            snapshots: vec![],
+            modified_keys: HashMap::new(),
+            modified_ranges: vec![],
+        };
+    }
+}
+
+impl ConflictTracker {
+    pub fn snapshot(&mut self) -> Arc<Snapshot> {
+        self.prune();
+        let snapshot = Arc::new(Snapshot { engine: self.engine, sequence: self.sequence });
+        self.snapshots.push(Arc::downgrade(&snapshot));
+        return snapshot;
+    }
+
+    pub fn record_key(&mut self, key: &str) {
+        self.sequence += 1;
+        if self.tracking() {
+            self.modified_keys.insert(key.to_owned(), self.sequence);
+        }
+    }
+
+    pub fn record_range(&mut self, start: &str, end: &str) {
+        self.sequence += 1;
+        if self.tracking() {
+            self.modified_ranges.push((start.to_owned(), end.to_owned(), self.sequence));
+        }
+    }
+
+    pub fn modified_since(&self, key: &str, snapshot: u64) -> bool {
+        if self.modified_keys.get(key).is_some_and(|sequence| *sequence > snapshot) {
+            return true;
+        }
+        return self
+            .modified_ranges
+            .iter()
+            .any(|(start, end, sequence)| *sequence > snapshot && start.as_str() <= key && key < end.as_str());
+    }
+
+    /// Whether any transaction is still open. Those dropped without committing are let go of here, and once none is
+    /// left every modification is forgotten.
+    pub fn tracking(&mut self) -> bool {
+        self.snapshots.retain(|snapshot| snapshot.strong_count() > 0);
+        if self.snapshots.is_empty() {
+            self.modified_keys.clear();
+            self.modified_ranges.clear();
+            return false;
+        }
+        return true;
+    }
+
+    /// Forgets modifications no open transaction can conflict with anymore.
+    pub fn prune(&mut self) {
+        self.snapshots.retain(|snapshot| snapshot.strong_count() > 0);
+        let oldest = self.snapshots.iter().filter_map(Weak::upgrade).map(|snapshot| snapshot.sequence).min();
+        match oldest {
+            Some(oldest) => {
+                self.modified_keys.retain(|_key, sequence| *sequence > oldest);
+                self.modified_ranges.retain(|(_start, _end, sequence)| *sequence > oldest);
+            }
+            None => {
+                self.modified_keys.clear();
+                self.modified_ranges.clear();
+            }
+        }
+    }
+}
+
+/// An optimistic read-write transaction.
+///
+/// Writes are buffered locally and only applied on [`commit`](Transaction::commit), which fails with
+/// [`Error::TransactionConflict`] if any key the transaction read or wrote was modified after it began, be it by a
+/// write or by the [`CompactionFilter`](crate::CompactionFilter) removing or changing it. A transaction can only be
+/// committed to the engine it began on.
+pub struct Transaction {
+    snapshot: Arc<Snapshot>,
+    reads: BTreeSet<String>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(snapshot: Arc<Snapshot>) -> Self {
+        return Transaction {
+            snapshot,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        };
+    }
+
+    /// Reads `key`, seeing the transaction's own uncommitted writes.
+    pub fn get(&mut self, lsm: &mut LSMEngine, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.to_owned());
+        return lsm.read(key);
+    }
+
+    pub fn put(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.writes.insert(key.to_owned(), None);
+    }
+
+    /// Validates the transaction against writes committed since it began and, if none conflict, applies
+    /// its writes atomically.
+    pub fn commit(self, lsm: &mut LSMEngine) -> Result<()> {
+        if self.snapshot.engine != lsm.conflicts.engine {
+            return Err(Error::ForeignTransaction);
+        }
+        let snapshot = self.snapshot.sequence;
+        let mut touched = self.reads.iter().chain(self.writes.keys());
+        if let Some(key) = touched.find(|key| lsm.conflicts.modified_since(key, snapshot)) {
+            return Err(Error::TransactionConflict { key: key.to_owned() });
+        }
+
+        drop(self.snapshot);
+        lsm.write_batch(to_batch(self.writes))?;
+        lsm.conflicts.prune();
+        Ok(())
+    }
+}
+
+fn to_batch(writes: BTreeMap<String, Option<String>>) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for (key, value) in writes {
+        match value {
+            Some(value) => batch.put(key, value),
+            None => batch.delete(&key),
+        }
+    }
+    return batch;
+}
+
+/// A pessimistic read-write transaction.
+///
+/// Every key the transaction writes, or reads through [`get_for_update`](PessimisticTransaction::get_for_update),
+/// stays locked until the transaction commits or is dropped, so competing transactions wait for each other
+/// instead of failing at commit. A wait that would deadlock fails with [`Error::Deadlock`], and one outlasting the
+/// engine's lock timeout with [`Error::LockTimeout`]. Plain writes on the engine do not take locks.
+///
+/// Locks are taken through the transaction alone, never while the engine is borrowed: when the engine is shared between
+/// threads, [`get_for_update`](PessimisticTransaction::get_for_update) only borrows it once the key is locked, and
+/// [`put`](PessimisticTransaction::put) and [`delete`](PessimisticTransaction::delete) are meant to be called without it,
+/// so a waiting transaction never holds up the one it is waiting on.
+pub struct PessimisticTransaction {
+    id: TransactionId,
+    locks: Arc<LockManager>,
+    lock_timeout: Duration,
+    held: BTreeSet<String>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl PessimisticTransaction {
+    pub(crate) fn new(locks: Arc<LockManager>, lock_timeout: Duration) -> Self {
+        return PessimisticTransaction {
+            id: locks.next_id(),
+            locks,
+            lock_timeout,
+            held: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        };
+    }
+
+    /// Locks `key` for the rest of the transaction.
+    pub fn lock(&mut self, key: &str) -> Result<()> {
+        if !self.held.contains(key) {
+            self.locks.lock(self.id, key, self.lock_timeout)?;
+            self.held.insert(key.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Reads `key` without locking it, seeing the transaction's own uncommitted writes.
+    pub fn get(&mut self, lsm: &mut LSMEngine, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        return lsm.read(key);
+    }
+
+    /// Locks `key`, then borrows the engine from `engine` to read it, e.g. `|| lsm.lock().unwrap()` for an engine
+    /// behind a mutex.
+    pub fn get_for_update<E: DerefMut<Target = LSMEngine>>(&mut self, engine: impl FnOnce() -> E, key: &str) -> Result<Option<String>> {
+        self.lock(key)?;
+        return self.get(&mut engine(), key);
+    }
+
+    pub fn put(&mut self, key: String, value: String) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        self.lock(key)?;
+        self.writes.insert(key.to_owned(), None);
+        Ok(())
+    }
+
+    /// Applies the buffered writes as a single WAL batch and releases every lock.
+    pub fn commit(mut self, lsm: &mut LSMEngine) -> Result<()> {
+        let writes = std::mem::take(&mut self.writes);
+        lsm.write_batch(to_batch(writes))?;
+        Ok(())
+    }
+
+    /// Discards the buffered writes and releases every lock.
+    pub fn rollback(self) {}
+}
+
+impl Drop for PessimisticTransaction {
+    fn drop(&mut self) {
+        self.locks.unlock(self.id, &self.held);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CompactionFilter, Decision, Error, LSMBuilder};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_commit() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().segment_size(2).inmemory_capacity(1).sparse_offset(1).build();
+        lsm.write("balance/a".to_owned(), "10".to_owned())?;
+
+        let mut txn = lsm.begin_transaction();
+        let a: i32 = txn.get(&mut lsm, "balance/a")?.unwrap().parse()?;
+        txn.put("balance/a".to_owned(), (a - 3).to_string());
+        txn.put("balance/b".to_owned(), "3".to_owned());
+        assert_eq!(txn.get(&mut lsm, "balance/b")?, Some("3".to_owned()));
+        assert_eq!(lsm.read("balance/b")?, None);
+
+        txn.commit(&mut lsm)?;
+        assert_eq!(lsm.read("balance/a")?, Some("7".to_owned()));
+        assert_eq!(lsm.read("balance/b")?, Some("3".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().build();
+        lsm.write("k1".to_owned(), "v1".to_owned())?;
+
+        let mut first = lsm.begin_transaction();
+        let mut second = lsm.begin_transaction();
+        first.get(&mut lsm, "k1")?;
+        second.get(&mut lsm, "k1")?;
+        first.put("k1".to_owned(), "first".to_owned());
+        second.put("k1".to_owned(), "second".to_owned());
+
+        first.commit(&mut lsm)?;
+        let result = second.commit(&mut lsm);
+        assert!(matches!(result, Err(Error::TransactionConflict { key }) if key == "k1"));
+        assert_eq!(lsm.read("k1")?, Some("first".to_owned()));
+
+        let mut third = lsm.begin_transaction();
+        third.get(&mut lsm, "k2")?;
+        lsm.delete_range("k0", "k9")?;
+        assert!(third.commit(&mut lsm).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_foreign_transaction() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().build();
+        let mut other = LSMBuilder::new().build();
+        let mut txn = lsm.begin_transaction();
+        txn.put("k1".to_owned(), "v1".to_owned());
+        assert!(matches!(txn.commit(&mut other), Err(Error::ForeignTransaction)));
+        assert_eq!(other.read("k1")?, None);
+        Ok(())
+    }
+
+    struct DropExpired;
+
+    impl CompactionFilter for DropExpired {
+        fn filter(&self, _key: &str, value: &str) -> Decision {
+            if value == "expired" {
+                return Decision::Remove;
+            }
+            return Decision::Keep;
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter_conflicts() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().compaction_filter(DropExpired).build();
+        lsm.write("k1".to_owned(), "expired".to_owned())?;
+        lsm.write("k2".to_owned(), "v2".to_owned())?;
+
+        let mut txn = lsm.begin_transaction();
+        assert_eq!(txn.get(&mut lsm, "k1")?, Some("expired".to_owned()));
+        txn.put("k3".to_owned(), "v3".to_owned());
+        let mut untouched = lsm.begin_transaction();
+        untouched.get(&mut lsm, "k2")?;
+        //the filter drops k1 from under the first transaction
+        lsm.compact()?;
+        assert!(matches!(txn.commit(&mut lsm), Err(Error::TransactionConflict { key }) if key == "k1"));
+        untouched.commit(&mut lsm)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_abandoned_transaction() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().build();
+        let abandoned = lsm.begin_transaction();
+        lsm.write("k1".to_owned(), "v1".to_owned())?;
+        lsm.delete_range("k0", "k9")?;
+        assert_eq!(lsm.conflicts.modified_keys.len(), 1);
+        drop(abandoned);
+        lsm.write("k2".to_owned(), "v2".to_owned())?;
+        assert!(lsm.conflicts.modified_keys.is_empty() && lsm.conflicts.modified_ranges.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pessimistic_commit() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut lsm = LSMBuilder::new().lock_timeout(Duration::from_millis(20)).build();
+        lsm.write("k1".to_owned(), "v1".to_owned())?;
+
+        let mut first = lsm.begin_pessimistic_transaction();
+        let mut second = lsm.begin_pessimistic_transaction();
+        assert_eq!(first.get_for_update(|| &mut lsm, "k1")?, Some("v1".to_owned()));
+        assert!(matches!(second.get_for_update(|| &mut lsm, "k1"), Err(Error::LockTimeout { .. })));
+        first.put("k1".to_owned(), "first".to_owned())?;
+        first.delete("k2")?;
+        first.commit(&mut lsm)?;
+
+        assert_eq!(second.get_for_update(|| &mut lsm, "k1")?, Some("first".to_owned()));
+        second.rollback();
+        assert_eq!(lsm.read("k1")?, Some("first".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pessimistic_counter_across_threads() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let lsm = Arc::new(Mutex::new(LSMBuilder::new().lock_timeout(Duration::from_secs(10)).build()));
+        lsm.lock().unwrap().write("counter".to_owned(), "0".to_owned())?;
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let lsm = lsm.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let mut txn = lsm.lock().unwrap().begin_pessimistic_transaction();
+                        let current: i32 = txn.get_for_update(|| lsm.lock().unwrap(), "counter").unwrap().unwrap().parse().unwrap();
+                        txn.put("counter".to_owned(), (current + 1).to_string()).unwrap();
+                        txn.commit(&mut lsm.lock().unwrap()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(lsm.lock().unwrap().read("counter")?, Some("100".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use crate::manifest::{self, Manifest, SegmentEntry};
+use crate::record::Record;
+use crate::sst::{Segment, SegmentReader};
+use crate::wal::WalReader;
+use crate::{LSMEngine, Result};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// An inconsistency found by [`LSMEngine::verify`] or [`verify_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An entry or record that can't be read, at `offset` in `file`.
+    Unreadable { file: String, offset: u64, error: String },
+    /// A key that doesn't come strictly after the one before it, in its segment or in the sorted run.
+    Unsorted { file: String, key: String, previous: String },
+    ChecksumMismatch { file: String, expected: u64, actual: u64 },
+    /// A sparse index entry that doesn't point at its key.
+    BadIndexEntry { key: String, reason: String },
+    /// A key stored in a segment that the bloom filter claims was never written.
+    BloomMiss { file: String, key: String },
+    /// The manifest disagrees with the files in the data directory, or with the engine.
+    Manifest(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Problem::Unreadable { file, offset, error } => write!(f, "{} at offset {}: {}", file, offset, error),
+            Problem::Unsorted { file, key, previous } => write!(f, "{}: {} comes after {}", file, key, previous),
+            Problem::ChecksumMismatch { file, expected, actual } => {
+                write!(f, "{}: checksum is {:016x} rather than {:016x}", file, actual, expected)
+            }
+            Problem::BadIndexEntry { key, reason } => write!(f, "sparse index entry for {}: {}", key, reason),
+            Problem::BloomMiss { file, key } => write!(f, "{}: the bloom filter does not contain {}", file, key),
+            Problem::Manifest(reason) => write!(f, "manifest: {}", reason),
+        };
+    }
+}
+
+//the keys of a segment, with their offsets, as read from its file
+type Keys = Vec<(u64, String)>;
+
+/// Reads a segment through, reporting the entries that can't be read or are out of order, and returns the keys read
+/// along with the checksum of the whole file.
+fn scan_segment(reader: SegmentReader, file: &str, problems: &mut Vec<Problem>) -> (Keys, u64) {
+    let mut reader = reader;
+    let mut keys: Keys = vec![];
+    loop {
+        let offset = reader.offset();
+        let (offset, record) = match reader.next() {
+            None => break,
+            Some(Ok(entry)) => entry,
+            Some(Err(error)) => {
+                problems.push(Problem::Unreadable { file: file.to_owned(), offset, error: error.to_string() });
+                continue;
+            }
+        };
+        let key = match record {
+            Record::Put { key, .. } | Record::Delete { key } | Record::Merge { key, .. } => key,
+            other => {
+                let error = format!("segments hold puts, deletes and merges only, not {:?}", other);
+                problems.push(Problem::Unreadable { file: file.to_owned(), offset, error });
+                continue;
+            }
+        };
+        if let Some((_offset, previous)) = keys.last().filter(|(_offset, previous)| *previous >= key) {
+            problems.push(Problem::Unsorted { file: file.to_owned(), key: key.clone(), previous: previous.clone() });
+        }
+        keys.push((offset, key));
+    }
+    return (keys, reader.checksum());
+}
+
+/// Reports the segments of the sorted run whose keys don't all come after the previous segment's.
+fn check_run(run: &[(String, Keys)], problems: &mut Vec<Problem>) {
+    for pair in run.windows(2) {
+        let (previous, next) = (pair[0].1.last(), pair[1].1.first());
+        if let (Some((_, previous)), Some((_, key))) = (previous, next) {
+            if previous >= key {
+                problems.push(Problem::Unsorted { file: pair[1].0.clone(), key: key.clone(), previous: previous.clone() });
+            }
+        }
+    }
+}
+
+fn check_checksum(file: &str, expected: u64, actual: u64, problems: &mut Vec<Problem>) {
+    if expected != actual {
+        problems.push(Problem::ChecksumMismatch { file: file.to_owned(), expected, actual });
+    }
+}
+
+/// Checks the data directory `dir` from outside the engine, without changing anything in it: every segment the
+/// manifest lists must be there, readable, sorted and match its checksum, the sorted run must be in order, the WALs
+/// must be readable, and the manifest must account for every file. Every problem found is reported, and an empty
+/// list means all is well.
+pub fn verify_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<Problem>> {
+    let dir = dir.as_ref();
+    let mut problems = vec![];
+    let manifest = match Manifest::load(dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return Ok(vec![Problem::Manifest(format!("{} has no manifest", dir.display()))]),
+        Err(error) => return Ok(vec![Problem::Manifest(format!("cannot be read: {}", error))]),
+    };
+
+    let scan = |entry: &SegmentEntry, problems: &mut Vec<Problem>| -> Result<Option<(String, Keys)>> {
+        let file = manifest::segment_file_name(entry.file);
+        let path = dir.join(&file);
+        if !path.exists() {
+            problems.push(Problem::Manifest(format!("{} is listed but missing", file)));
+            return Ok(None);
+        }
+        let (keys, checksum) = scan_segment(SegmentReader::open(&path)?, &file, problems);
+        if let Some(expected) = entry.checksum {
+            check_checksum(&file, expected, checksum, problems);
+        }
+        return Ok(Some((file, keys)));
+    };
+    for entry in manifest.level0.iter() {
+        scan(entry, &mut problems)?;
+    }
+    let mut run = vec![];
+    for entry in manifest.run.iter() {
+        run.extend(scan(entry, &mut problems)?);
+    }
+    check_run(&run, &mut problems);
+
+    let mut listed = manifest.level0.iter().chain(manifest.run.iter()).map(|entry| entry.file).collect::<Vec<_>>();
+    let wals = manifest.archived_wals.iter().map(|entry| entry.file).chain(manifest.wal).collect::<Vec<_>>();
+    listed.extend(wals.iter());
+    for number in wals {
+        let file = manifest::wal_file_name(number);
+        let path = dir.join(&file);
+        if path.exists() {
+            let mut reader = WalReader::open(&path)?;
+            loop {
+                let offset = reader.offset();
+                match reader.next() {
+                    None => break,
+                    Some(Ok(_record)) => {}
+                    Some(Err(error)) => problems.push(Problem::Unreadable { file: file.clone(), offset, error: error.to_string() }),
+                }
+            }
+        } else {
+            problems.push(Problem::Manifest(format!("{} is listed but missing", file)));
+        }
+    }
+    let mut sorted = listed.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    if sorted.len() != listed.len() {
+        problems.push(Problem::Manifest("a file is listed more than once".to_owned()));
+    }
+    if let Some(number) = sorted.last().filter(|number| **number >= manifest.next_file) {
+        problems.push(Problem::Manifest(format!("file {} is not below the next file number {}", number, manifest.next_file)));
+    }
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if manifest::file_number(&name).is_some_and(|number| !listed.contains(&number)) {
+            problems.push(Problem::Manifest(format!("{} is not listed", name)));
+        }
+    }
+    return Ok(problems);
+}
+
+/// Checks a running engine; see [`LSMEngine::verify`].
+pub(crate) fn engine(lsm: &mut LSMEngine) -> Result<Vec<Problem>> {
+    let mut problems = vec![];
+    let name = |segment: &Segment, level: usize, index: usize| match segment.number() {
+        Some(number) => manifest::segment_file_name(number),
+        None => format!("level {} segment {}", level, index),
+    };
+
+    //the segments' files against what was written to them
+    let bloom_filter = &lsm.bloom_filter;
+    let scan = |segment: &mut Segment, file: String, problems: &mut Vec<Problem>| -> Result<(String, Keys)> {
+        let (keys, checksum) = segment.read_entries(|reader| scan_segment(reader, &file, problems))?;
+        check_checksum(&file, segment.checksum(), checksum, problems);
+        for (_offset, key) in keys.iter() {
+            if !bloom_filter.contains(&key.as_str()) {
+                problems.push(Problem::BloomMiss { file: file.clone(), key: key.clone() });
+            }
+        }
+        return Ok((file, keys));
+    };
+    for (index, segment) in lsm.level0.iter_mut().enumerate() {
+        let file = name(segment, 0, index);
+        scan(segment, file, &mut problems)?;
+    }
+    let mut run = vec![];
+    for (index, segment) in lsm.segments.iter_mut().enumerate() {
+        let file = name(segment, 1, index);
+        run.push(scan(segment, file, &mut problems)?);
+    }
+    check_run(&run, &mut problems);
+
+    //the sparse index against the sorted run
+    for (key, (offset, index)) in lsm.sparse_memory_index.iter() {
+        let reason = match run.get(*index) {
+            None => format!("segment {} is past the end of the sorted run", index),
+            Some((file, keys)) => match keys.binary_search_by_key(offset, |(offset, _key)| *offset) {
+                Ok(found) if keys[found].1 == *key => continue,
+                Ok(found) => format!("offset {} in {} holds {}", offset, file, keys[found].1),
+                Err(_) => format!("no entry starts at offset {} in {}", offset, file),
+            },
+        };
+        problems.push(Problem::BadIndexEntry { key: key.clone(), reason });
+    }
+    //a search can only start from an indexed key at or before the one it looks for
+    let first_indexed = lsm.sparse_memory_index.keys().next();
+    if let Some((_offset, first)) = run.iter().find_map(|(_file, keys)| keys.first()) {
+        if first_indexed != Some(first) {
+            problems.push(Problem::BadIndexEntry { key: first.clone(), reason: "the first key of the sorted run is not indexed".to_owned() });
+        }
+    }
+
+    //the data directory against the engine
+    if let Some(storage) = lsm.storage.as_ref() {
+        match Manifest::load(storage.dir()) {
+            Ok(Some(manifest)) => {
+                let expected = |segments: &[Segment]| segments.iter().map(|segment| (segment.number(), Some(segment.checksum()))).collect::<Vec<_>>();
+                let listed = |entries: &[SegmentEntry]| entries.iter().map(|entry| (Some(entry.file), entry.checksum)).collect::<Vec<_>>();
+                if listed(&manifest.level0) != expected(&lsm.level0) {
+                    problems.push(Problem::Manifest("level 0 does not match the engine's".to_owned()));
+                }
+                if listed(&manifest.run) != expected(&lsm.segments) {
+                    problems.push(Problem::Manifest("the sorted run does not match the engine's".to_owned()));
+                }
+                if manifest.wal != lsm.wal_number {
+                    problems.push(Problem::Manifest("the WAL does not match the engine's".to_owned()));
+                }
+                for entry in manifest.level0.iter().chain(manifest.run.iter()) {
+                    if !storage.segment_path(entry.file).exists() {
+                        problems.push(Problem::Manifest(format!("{} is listed but missing", manifest::segment_file_name(entry.file))));
+                    }
+                }
+            }
+            Ok(None) => problems.push(Problem::Manifest("the data directory has no manifest".to_owned())),
+            Err(error) => problems.push(Problem::Manifest(format!("cannot be read: {}", error))),
+        }
+    }
+    return Ok(problems);
+}
@@ -1,5 +1,8 @@
 use std::fs::File;
-use crate::kv::{KVFileWriter, KVFileIterator, KVFileReader};
+use std::io::{BufReader, Write};
+use std::path::Path;
+use crate::kv::{KVFileIterator, KVFileReader, KVPair, LineReader, Result};
+use crate::record::Record;
 
 
 pub struct Wal {
@@ -15,12 +18,49 @@ impl KVFileIterator for Wal {
 
 impl KVFileReader for Wal {}
 
-impl KVFileWriter for Wal {}
-
 impl Wal {
     pub fn new(f: File) -> Self {
         return Wal {
             file: f
         };
     }
-}
\ No newline at end of file
+
+    /// Appends `records` with a single write, flushing them to disk first if `sync` is set.
+    pub fn append(&mut self, records: &[KVPair], sync: bool) -> Result<()> {
+        let mut buffer = vec![];
+        for record in records {
+            serde_json::to_writer(&mut buffer, record)?;
+            buffer.push(b'\n');
+        }
+        self.file.write_all(&buffer)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the records of a WAL file one by one, for tools looking at it from outside the engine.
+pub struct WalReader {
+    lines: LineReader<BufReader<File>>,
+}
+
+impl WalReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        return Ok(WalReader { lines: LineReader::new(BufReader::new(File::open(path)?)) });
+    }
+
+    /// The offset of the next record.
+    pub fn offset(&self) -> u64 {
+        return self.lines.offset();
+    }
+}
+
+impl Iterator for WalReader {
+    type Item = crate::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_offset, kv) = self.lines.next()?;
+        return Some(kv.map(Record::decode).map_err(crate::Error::from));
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A memory budget shared by the memtables of several engines, registered with
+/// [`LSMBuilder::write_buffer_manager`](crate::LSMBuilder::write_buffer_manager).
+///
+/// Every memtable charges its entries to the manager. Once their sum reaches the budget, the next engine to take a
+/// write flushes its memtable, whatever its own `write_buffer_size`. Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct WriteBufferManager {
+    budget: usize,
+    usage: Arc<AtomicUsize>,
+}
+
+impl WriteBufferManager {
+    pub fn new(budget: usize) -> Self {
+        return WriteBufferManager { budget, usage: Arc::new(AtomicUsize::new(0)) };
+    }
+
+    pub fn budget(&self) -> usize {
+        return self.budget;
+    }
+
+    /// Bytes held by all memtables charged to this manager.
+    pub fn memory_usage(&self) -> usize {
+        return self.usage.load(Ordering::Relaxed);
+    }
+
+    pub fn over_budget(&self) -> bool {
+        return self.memory_usage() >= self.budget;
+    }
+
+    pub(crate) fn reserve(&self, bytes: usize) {
+        self.usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn free(&self, bytes: usize) {
+        self.usage.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+/// What the engine does with an incoming write, given how far compaction has fallen behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Condition {
+    Normal,
+    /// The write is delayed to the `delayed_write_rate`.
+    Delayed,
+    /// The write waits until the unmerged segments have been merged.
+    Stopped,
+}
+
+/// The write-slowdown and write-stop triggers configured on the builder. Unset triggers never fire.
+#[derive(Debug, Clone)]
+pub(crate) struct WriteStall {
+    pub slowdown_segments: Option<usize>,
+    pub stop_segments: Option<usize>,
+    pub slowdown_pending_bytes: Option<u64>,
+    pub stop_pending_bytes: Option<u64>,
+    pub delayed_write_rate: u64,
+}
+
+impl Default for WriteStall {
+    fn default() -> Self {
+        return WriteStall {
+            slowdown_segments: None,
+            stop_segments: None,
+            slowdown_pending_bytes: None,
+            stop_pending_bytes: None,
+            delayed_write_rate: 16 * 1024 * 1024,
+        };
+    }
+}
+
+impl WriteStall {
+    pub fn condition(&self, unmerged_segments: usize, pending_bytes: u64) -> Condition {
+        let reached = |segments: Option<usize>, bytes: Option<u64>| {
+            segments.is_some_and(|limit| unmerged_segments >= limit) || bytes.is_some_and(|limit| pending_bytes >= limit)
+        };
+        if reached(self.stop_segments, self.stop_pending_bytes) {
+            return Condition::Stopped;
+        }
+        if reached(self.slowdown_segments, self.slowdown_pending_bytes) {
+            return Condition::Delayed;
+        }
+        return Condition::Normal;
+    }
+
+    /// How long a delayed write of `bytes` is held back.
+    pub fn delay(&self, bytes: usize) -> Duration {
+        return Duration::from_secs_f64(bytes as f64 / self.delayed_write_rate.max(1) as f64);
+    }
+}